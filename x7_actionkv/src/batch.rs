@@ -0,0 +1,179 @@
+//! several writes that either all make it into the store or none of them do
+//!
+//! on disk a batch is framed by two marker records (empty key, the number of writes in the batch as the value):
+//! BATCH_BEGIN, then every write flagged as BATCHED, then BATCH_COMMIT
+//! load() holds on to batched records until it sees the commit, so a crash half way through a batch leaves no trace in the index
+//! (so does everything else that reads the log, through Batches)
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ByteStr, ByteString, Record};
+use crate::{FLAG_BATCHED, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT};
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    /// None means delete
+    pub(crate) ops: Vec<(ByteString, Option<ByteString>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> &mut Self {
+        self.ops.push((key.to_vec(), Some(value.to_vec())));
+        self
+    }
+
+    pub fn delete(&mut self, key: &ByteStr) -> &mut Self {
+        self.ops.push((key.to_vec(), None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// what a record amounts to once the batch framing around it has been taken into account
+pub(crate) enum Framed<T> {
+    /// an ordinary write outside of any batch - over to the caller
+    Single(Record),
+    /// the commit marker of a complete batch - every write in it, in the order they were written
+    Committed(Vec<T>),
+    /// a marker, or a batched write that's waiting for its commit (or was dropped because its batch can't be complete)
+    Held,
+}
+
+/// the batch state machine, for anything that reads records front to back: load(), subscriptions, compact_segments()
+/// T is whatever the reader needs to remember about each batched write until the batch commits
+pub(crate) struct Batches<T> {
+    /// a batch we've seen the begin marker of, but not (yet) the commit
+    pending: Option<PendingBatch<T>>,
+}
+
+struct PendingBatch<T> {
+    /// how many writes the BATCH_BEGIN marker announced
+    expected: u32,
+    ops: Vec<T>,
+    /// recover() had to skip a record while this batch was open, so it can't be applied in full any more
+    poisoned: bool,
+    /// where the begin marker is
+    start: u64,
+}
+
+impl<T> Batches<T> {
+    pub(crate) fn new() -> Self {
+        Batches { pending: None }
+    }
+
+    /// feeds in the record that starts at offset start - batched() turns a write held back for its batch into a T
+    pub(crate) fn next<F>(&mut self, record: Record, start: u64, batched: F) -> Framed<T>
+    where
+        F: FnOnce(Record) -> T,
+    {
+        //a batch that was still open when something else got written was cut short by a crash - it never happened
+        if !record.has_flag(FLAG_BATCHED) && !record.has_flag(FLAG_BATCH_COMMIT) {
+            self.pending = None;
+        }
+        if record.has_flag(FLAG_BATCH_BEGIN) {
+            self.pending = Some(PendingBatch { expected: record.batch_count(), ops: vec![], poisoned: false, start });
+            return Framed::Held;
+        }
+        if record.has_flag(FLAG_BATCH_COMMIT) {
+            // the commit marker repeats the count - anything else means the markers don't belong together
+            return match self.pending.take() {
+                Some(batch) if !batch.poisoned && batch.expected == record.batch_count() && batch.ops.len() == batch.expected as usize => {
+                    Framed::Committed(batch.ops)
+                },
+                _ => Framed::Held,
+            };
+        }
+        if record.has_flag(FLAG_BATCHED) {
+            //no open batch means its begin marker was lost, so the batch can't have been complete either
+            if let Some(batch) = self.pending.as_mut() {
+                batch.ops.push(batched(record));
+            }
+            return Framed::Held;
+        }
+        Framed::Single(record)
+    }
+
+    /// a record had to be skipped - we can't tell whether it belonged to the open batch, so the batch can't be trusted
+    pub(crate) fn poison(&mut self) {
+        if let Some(batch) = self.pending.as_mut() {
+            batch.poisoned = true;
+        }
+    }
+
+    /// where the open batch's begin marker is, if there is one
+    pub(crate) fn open_at(&self) -> Option<u64> {
+        self.pending.as_ref().map(|batch| batch.start)
+    }
+
+    /// drops the open batch, eg when moving on to another segment (a batch never spans two)
+    pub(crate) fn clear(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::data_file;
+    use crate::{ActionKV, MemoryStorage, Options};
+    use crate::{FLAG_BATCHED, FLAG_BATCH_BEGIN, FLAG_BATCH_COMMIT, FLAG_TOMBSTONE};
+
+    fn loaded(data: Vec<u8>) -> ActionKV {
+        let mut store = ActionKV::with_storage(MemoryStorage::from(data), Options::default()).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    #[test]
+    fn load_applies_a_committed_batch() {
+        let two = 2u32.to_le_bytes();
+        let store = loaded(data_file(&[
+            (b"a", b"1", 0),
+            (b"", &two, FLAG_BATCH_BEGIN),
+            (b"b", b"2", FLAG_BATCHED),
+            (b"a", b"", FLAG_BATCHED | FLAG_TOMBSTONE),
+            (b"", &two, FLAG_BATCH_COMMIT),
+        ]));
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn load_ignores_a_batch_without_its_commit() {
+        let two = 2u32.to_le_bytes();
+        // the crash came before the commit marker made it to disk
+        let store = loaded(data_file(&[
+            (b"a", b"1", 0),
+            (b"", &two, FLAG_BATCH_BEGIN),
+            (b"b", b"2", FLAG_BATCHED),
+            (b"a", b"", FLAG_BATCHED | FLAG_TOMBSTONE),
+        ]));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn load_ignores_a_batch_cut_short_by_another_write() {
+        let two = 2u32.to_le_bytes();
+        // only one of the two writes, then whatever got written after the crash - the commit doesn't belong to it either
+        let store = loaded(data_file(&[
+            (b"", &two, FLAG_BATCH_BEGIN),
+            (b"b", b"2", FLAG_BATCHED),
+            (b"c", b"3", 0),
+            (b"", &two, FLAG_BATCH_COMMIT),
+        ]));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+}
@@ -0,0 +1,216 @@
+//! keeps recently read values in memory, so a hot key isn't read back and checksummed on every get()
+//! least recently used goes first once the cache is over its size limit
+//!
+//! writers drop a key's entry while they hold the state lock for the index update, and get() only fills one in
+//! while holding the state lock and after checking its position is still current - so the cache can't hand out a value that's been overwritten
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::{is_expired, ByteStr, ByteString};
+
+/// how the cache is doing, see ActionKV::cache_stats()
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    /// get()s answered from memory
+    pub hits: u64,
+    /// get()s that had to go to disk, for a key that exists or not
+    pub misses: u64,
+    pub entries: usize,
+    /// keys and values of everything cached, bookkeeping not included
+    pub bytes: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Cache {
+    max_bytes: u64,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<ByteString, Entry>,
+    /// keys by when they were last used, oldest first
+    order: BTreeMap<u64, ByteString>,
+    /// goes up by one on every use
+    clock: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: ByteString,
+    expires_at: Option<u64>,
+    used: u64,
+}
+
+impl Cache {
+    /// max_bytes of 0 turns the cache off - nothing is ever kept
+    pub(crate) fn new(max_bytes: u64) -> Self {
+        Cache { max_bytes, lru: Mutex::new(Lru::default()), hits: AtomicU64::new(0), misses: AtomicU64::new(0) }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    /// Some(value) if key is cached and hasn't expired - counts as a hit or a miss either way
+    pub(crate) fn get(&self, key: &ByteStr, now: u64) -> Option<ByteString> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        let found = match lru.entries.get(key) {
+            Some(entry) if !is_expired(entry.expires_at, now) => Some(entry.value.clone()),
+            // an expired value is as good as gone
+            Some(_) => {
+                lru.remove(key);
+                None
+            },
+            None => None,
+        };
+        match found {
+            Some(_) => {
+                lru.touch(key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+            },
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            },
+        }
+        found
+    }
+
+    /// values bigger than the whole cache aren't worth evicting everything else for, so they're left out
+    pub(crate) fn insert(&self, key: &ByteStr, value: &ByteStr, expires_at: Option<u64>) {
+        let size = (key.len() + value.len()) as u64;
+        if !self.is_enabled() || size > self.max_bytes {
+            return;
+        }
+        let mut lru = self.lru.lock().unwrap();
+        lru.remove(key);
+        lru.clock += 1;
+        let used = lru.clock;
+        lru.entries.insert(key.to_vec(), Entry { value: value.to_vec(), expires_at, used });
+        lru.order.insert(used, key.to_vec());
+        lru.bytes += size;
+        while lru.bytes > self.max_bytes {
+            let oldest = match lru.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            lru.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn invalidate(&self, key: &ByteStr) {
+        if self.is_enabled() {
+            self.lru.lock().unwrap().remove(key);
+        }
+    }
+
+    pub(crate) fn clear(&self) {
+        if self.is_enabled() {
+            *self.lru.lock().unwrap() = Lru::default();
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let lru = self.lru.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: lru.entries.len(),
+            bytes: lru.bytes,
+        }
+    }
+}
+
+impl Lru {
+    fn touch(&mut self, key: &ByteStr) {
+        self.clock += 1;
+        let used = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let key = self.order.remove(&entry.used).expect("every entry is in order");
+            entry.used = used;
+            self.order.insert(used, key);
+        }
+    }
+
+    fn remove(&mut self, key: &ByteStr) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+            self.bytes -= (key.len() + entry.value.len()) as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ActionKV, MemoryStorage, Options, WriteBatch};
+
+    /// reads a twice - the second one should come from the cache
+    fn read_twice(store: &ActionKV) -> Option<ByteString> {
+        let first = store.get(b"a").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), first);
+        first
+    }
+
+    #[test]
+    fn every_kind_of_write_drops_the_cached_value() {
+        let store = ActionKV::with_storage(MemoryStorage::new(), Options { cache_size: 1024, ..Options::default() }).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        assert_eq!(read_twice(&store), Some(b"1".to_vec()));
+        assert_eq!(store.cache_stats().hits, 1);
+
+        store.insert(b"a", b"2").unwrap();
+        assert_eq!(read_twice(&store), Some(b"2".to_vec()));
+
+        store.delete(b"a").unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+
+        let mut batch = WriteBatch::new();
+        batch.insert(b"a", b"3").insert(b"b", b"4");
+        store.write(&batch).unwrap();
+        assert_eq!(read_twice(&store), Some(b"3".to_vec()));
+
+        let mut batch = WriteBatch::new();
+        batch.delete(b"a");
+        store.write(&batch).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+
+        // compact() moves the records, but the values stay what they were
+        store.insert(b"a", b"5").unwrap();
+        assert_eq!(read_twice(&store), Some(b"5".to_vec()));
+        store.compact().unwrap();
+        assert_eq!(read_twice(&store), Some(b"5".to_vec()));
+        store.insert(b"a", b"6").unwrap();
+        assert_eq!(read_twice(&store), Some(b"6".to_vec()));
+    }
+
+    #[test]
+    fn the_least_recently_used_go_first_and_expired_values_are_misses() {
+        // room for two one byte keys with one byte values
+        let cache = Cache::new(4);
+        cache.insert(b"a", b"1", None);
+        cache.insert(b"b", b"2", None);
+        assert_eq!(cache.get(b"a", 0), Some(b"1".to_vec()));
+        cache.insert(b"c", b"3", None);
+        // b was used longest ago
+        assert_eq!(cache.get(b"b", 0), None);
+        assert_eq!(cache.get(b"a", 0), Some(b"1".to_vec()));
+        assert_eq!(cache.get(b"c", 0), Some(b"3".to_vec()));
+
+        cache.insert(b"d", b"4", Some(10));
+        assert_eq!(cache.get(b"d", 9), Some(b"4".to_vec()));
+        assert_eq!(cache.get(b"d", 10), None);
+        // too big to be worth keeping
+        cache.insert(b"e", b"long", None);
+        assert_eq!(cache.get(b"e", 0), None);
+        assert_eq!(cache.stats().bytes, 2);
+    }
+}
@@ -0,0 +1,49 @@
+//! values can be compressed on their way to disk - handy when they're big and repetitive, like JSON
+//! each record says for itself whether its value is compressed, so a store can hold a mix of both
+//! and files written before compression existed load exactly as they did
+
+use std::io;
+
+use crate::{ByteStr, ByteString};
+
+/// how values get stored - set through Options::compression
+/// only affects new writes (and whatever compact() rewrites), reading works whatever the setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// store values as they are
+    #[default]
+    None,
+    /// compress values with LZ4 - fast, and decent on text
+    /// a value that doesn't get any smaller is stored as it is anyway
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+
+impl Compression {
+    /// the value as it should be written, or None if it's better off left alone
+    #[cfg_attr(not(feature = "lz4"), allow(unused_variables))]
+    pub(crate) fn compress(self, value: &ByteStr) -> Option<ByteString> {
+        match self {
+            Compression::None => None,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                // the original length goes in front, so decompress() knows how much room to make
+                let compressed = lz4_flex::compress_prepend_size(value);
+                if compressed.len() < value.len() { Some(compressed) } else { None }
+            },
+        }
+    }
+}
+
+/// undoes compress() - only ever called on a record whose checksum has already been checked
+#[cfg(feature = "lz4")]
+pub(crate) fn decompress(value: &ByteStr) -> io::Result<ByteString> {
+    lz4_flex::decompress_size_prepended(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// a compressed record in a store opened by a build without the lz4 feature
+/// not InvalidData - that means a bad checksum, and would get the perfectly good record quarantined by recover()
+#[cfg(not(feature = "lz4"))]
+pub(crate) fn decompress(_value: &ByteStr) -> io::Result<ByteString> {
+    Err(io::Error::other("record is compressed, but libactionkv was built without the lz4 feature"))
+}
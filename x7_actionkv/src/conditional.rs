@@ -0,0 +1,148 @@
+//! writes that depend on what's already there: insert_if_absent(), compare_and_swap() and increment()
+//! each one reads the current value and writes the new one without letting go of the write lock in between,
+//! so no other writer on the same store can slip in and change the key under it
+//! (readers aren't held up - they see either the old value or the new one)
+
+use std::io;
+use std::sync::Arc;
+
+use crate::{now_millis, ActionKV, ByteStr, Record, WriteGuard};
+
+impl ActionKV {
+    /// inserts value unless key already has one - returns whether it did
+    pub fn insert_if_absent(&self, key: &ByteStr, value: &ByteStr) -> io::Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// sets key to new (or deletes it, for None) only if its value is currently expected (None meaning no value at all)
+    /// returns whether it did - false means someone else got there first, read the key again and decide what to do
+    /// eg releasing a lock only if we're still the ones holding it: `compare_and_swap(b"lock", Some(b"me"), None)`
+    pub fn compare_and_swap(&self, key: &ByteStr, expected: Option<&ByteStr>, new: Option<&ByteStr>) -> io::Result<bool> {
+        // a read-only store would otherwise answer false for a mismatch, as if it could have written
+        self.check_writable()?;
+        let writer = self.writer.lock().unwrap();
+        let current = self.current(&writer, key)?;
+        if current.as_ref().map(|record| record.kv.value.as_slice()) != expected {
+            return Ok(false);
+        }
+        let sync_target = match new {
+            Some(new) => Some(self.put(&writer, key, new, None)?),
+            None => self.tombstone(&writer, key)?,
+        };
+        drop(writer);
+        if let Some(sync_target) = sync_target {
+            self.syncer.commit(sync_target)?;
+        }
+        Ok(true)
+    }
+
+    /// adds by to the counter at key and returns the result
+    /// counters are stored as decimal text (eg b"42"), and a missing key counts as 0
+    /// a key with a ttl keeps it - fails with InvalidData if the value isn't a number, and InvalidInput if it would overflow
+    pub fn increment(&self, key: &ByteStr, by: i64) -> io::Result<i64> {
+        self.check_writable()?;
+        let writer = self.writer.lock().unwrap();
+        let (count, expires_at) = match self.current(&writer, key)? {
+            Some(record) => (parse_counter(&record.kv.value)?, record.expires_at),
+            None => (0, None),
+        };
+        let count = count
+            .checked_add(by)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "increment would overflow"))?;
+        let sync_target = self.put(&writer, key, count.to_string().as_bytes(), expires_at)?;
+        drop(writer);
+        self.syncer.commit(sync_target)?;
+        Ok(count)
+    }
+
+    /// key's live record, straight from disk - with the write lock held nobody can replace it until we're done
+    fn current(&self, _writer: &WriteGuard, key: &ByteStr) -> io::Result<Option<Record>> {
+        let (position, segment) = {
+            let state = self.state();
+            match state.lookup(key)? {
+                Some(position) => (position, Arc::clone(state.segment(position.segment)?)),
+                None => return Ok(None),
+            }
+        };
+        let record = ActionKV::read_record(&segment, position)?;
+        // an expired value is as good as no value
+        if record.is_expired(now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+}
+
+fn parse_counter(value: &ByteStr) -> io::Result<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "value is not an integer"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn compare_and_swap_only_writes_over_what_was_expected() {
+        let store = ActionKV::in_memory();
+        assert!(store.insert_if_absent(b"lock", b"me").unwrap());
+        assert!(!store.insert_if_absent(b"lock", b"you").unwrap());
+
+        // a mismatch doesn't write anything at all
+        let end = store.log_end().unwrap();
+        assert!(!store.compare_and_swap(b"lock", Some(b"you"), None).unwrap());
+        assert_eq!(store.log_end().unwrap(), end);
+        assert_eq!(store.get(b"lock").unwrap(), Some(b"me".to_vec()));
+
+        assert!(store.compare_and_swap(b"lock", Some(b"me"), Some(b"still me")).unwrap());
+        assert!(store.compare_and_swap(b"lock", Some(b"still me"), None).unwrap());
+        assert_eq!(store.get(b"lock").unwrap(), None);
+        // deleting what's already gone
+        assert!(store.compare_and_swap(b"lock", None, None).unwrap());
+
+        // an expired value is as good as none
+        store.insert_expiring(b"lease", b"old", Some(now_millis() - 1)).unwrap();
+        assert!(store.insert_if_absent(b"lease", b"new").unwrap());
+        assert_eq!(store.get(b"lease").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn increment_counts_in_decimal_and_keeps_the_ttl() {
+        let store = ActionKV::in_memory();
+        assert_eq!(store.increment(b"n", 5).unwrap(), 5);
+        assert_eq!(store.increment(b"n", -7).unwrap(), -2);
+        assert_eq!(store.get(b"n").unwrap(), Some(b"-2".to_vec()));
+
+        let expires_at = now_millis() + 3_600_000;
+        store.insert_expiring(b"t", b"1", Some(expires_at)).unwrap();
+        assert_eq!(store.increment(b"t", 1).unwrap(), 2);
+        let writer = store.writer.lock().unwrap();
+        assert_eq!(store.current(&writer, b"t").unwrap().unwrap().expires_at, Some(expires_at));
+        drop(writer);
+
+        store.insert(b"text", b"one").unwrap();
+        assert_eq!(store.increment(b"text", 1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        store.insert(b"max", i64::MAX.to_string().as_bytes()).unwrap();
+        assert_eq!(store.increment(b"max", 1).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.get(b"max").unwrap(), Some(i64::MAX.to_string().into_bytes()));
+    }
+
+    #[test]
+    fn increments_from_many_threads_all_count() {
+        let store = ActionKV::in_memory();
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        store.increment(b"n", 1).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get(b"n").unwrap(), Some(b"400".to_vec()));
+    }
+}
@@ -0,0 +1,207 @@
+//! when do appended records actually get forced out of the OS page cache and onto the disk?
+//! until they do, a power cut can lose them even though insert() already returned Ok
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// never sync ourselves, the OS writes things out whenever it likes - fastest, but recent writes can vanish
+    #[default]
+    OsManaged,
+    /// every write is synced before it returns
+    /// writers waiting at the same time share a single sync (group commit), so this costs less than one sync per write
+    SyncEveryWrite,
+    /// sync once `interval` has passed or `bytes` have been written since the last sync, whichever comes first
+    /// at most that much is lost on a power cut - open() refuses an interval of zero
+    Periodic { interval: Duration, bytes: u64 },
+}
+
+impl Durability {
+    /// a zero interval would have the background thread spin on the lock for as long as the store is open
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match self {
+            Durability::Periodic { interval, .. } if interval.is_zero() => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "a periodic sync needs an interval longer than zero"))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// keeps track of how much of what's been appended to the active segment is on disk yet, and syncs according to the mode
+/// the counters are totals since the store was opened rather than offsets, so they keep working across segments
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    mode: Durability,
+    state: Mutex<SyncState>,
+    /// notified whenever a sync finishes
+    synced: Condvar,
+}
+
+#[derive(Debug)]
+struct SyncState {
+    /// a handle on the segment currently being appended to
+    file: Arc<dyn Storage>,
+    written: u64,
+    synced: u64,
+    /// someone is in the middle of a sync - everyone else waits for it instead of starting their own
+    syncing: bool,
+    last_sync: Instant,
+    /// a background sync that failed, handed to the next writer - we can't just drop it, the data may be gone
+    error: Option<io::Error>,
+}
+
+impl Syncer {
+    pub(crate) fn new(mode: Durability, file: Arc<dyn Storage>) -> Arc<Syncer> {
+        let syncer = Arc::new(Syncer {
+            mode,
+            state: Mutex::new(SyncState {
+                file,
+                written: 0,
+                synced: 0,
+                syncing: false,
+                last_sync: Instant::now(),
+                error: None,
+            }),
+            synced: Condvar::new(),
+        });
+
+        // a quiet store would otherwise never get past the bytes threshold, so a thread takes care of the interval
+        // it only holds a Weak, so it winds down on its own once the store is dropped
+        if let Durability::Periodic { interval, .. } = mode {
+            let weak = Arc::downgrade(&syncer);
+            thread::spawn(move || Syncer::sync_periodically(weak, interval));
+        }
+
+        syncer
+    }
+
+    fn sync_periodically(weak: Weak<Syncer>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            let syncer = match weak.upgrade() {
+                Some(syncer) => syncer,
+                None => return,
+            };
+            let due = {
+                let state = syncer.state.lock().unwrap();
+                state.written > state.synced && state.last_sync.elapsed() >= interval
+            };
+            if due {
+                if let Err(e) = syncer.sync() {
+                    syncer.state.lock().unwrap().error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// hands over the error of a background sync that failed, if there was one
+    /// call before appending anything - once the bytes are in the file the write has to go through,
+    /// failing it then would leave a record in the log that the index (and the caller) know nothing about
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match self.state.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// call after appending len bytes to the active segment, while still the only writer
+    /// returns what to pass to commit() afterwards
+    pub(crate) fn written(&self, len: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += len;
+        state.written
+    }
+
+    /// call once the writer has let go of the store, with whatever written() returned
+    /// depending on the mode this returns straight away or only once those bytes are on disk
+    /// meanwhile other writers can append, and their bytes get carried along by the same sync
+    pub(crate) fn commit(&self, target: u64) -> io::Result<()> {
+        match self.mode {
+            Durability::OsManaged => Ok(()),
+            Durability::SyncEveryWrite => self.sync_up_to(target),
+            Durability::Periodic { bytes, .. } => {
+                let due = {
+                    let state = self.state.lock().unwrap();
+                    state.written - state.synced >= bytes
+                };
+                if due { self.sync() } else { Ok(()) }
+            },
+        }
+    }
+
+    /// syncs everything written so far
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        let target = self.state.lock().unwrap().written;
+        self.sync_up_to(target)
+    }
+
+    /// the active segment is about to change - whatever went into the old one is synced first
+    pub(crate) fn switch_file(&self, file: Arc<dyn Storage>) -> io::Result<()> {
+        self.sync()?;
+        self.state.lock().unwrap().file = file;
+        Ok(())
+    }
+
+    /// group commit: whoever finds nobody syncing becomes the leader and syncs everything written so far,
+    /// including what other writers appended while it was waiting - they find their bytes already covered and return
+    fn sync_up_to(&self, target: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= target {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+
+        state.syncing = true;
+        let up_to = state.written;
+        let file = Arc::clone(&state.file);
+        // don't hold the lock during the slow part, so that other writers can keep piling up behind us
+        drop(state);
+
+        // sync_data() skips metadata like modification times - the file length is still synced, which is all we need
+        let result = file.sync();
+
+        let mut state = self.state.lock().unwrap();
+        state.syncing = false;
+        if result.is_ok() {
+            state.synced = state.synced.max(up_to);
+            state.last_sync = Instant::now();
+        }
+        self.synced.notify_all();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use crate::ActionKV;
+
+    #[test]
+    fn a_failed_background_sync_fails_the_next_write_before_it_lands() {
+        let store = ActionKV::in_memory();
+        store.insert(b"a", b"1").unwrap();
+        let len = store.state().active().len().unwrap();
+        store.syncer.state.lock().unwrap().error = Some(io::Error::other("disk on fire"));
+
+        assert!(store.insert(b"b", b"2").is_err());
+        // nothing was appended, so there's nothing for a later load() or a subscriber to find
+        assert_eq!(store.state().active().len().unwrap(), len);
+        assert_eq!(store.get(b"b").unwrap(), None);
+
+        // the error is handed out once, after that writes carry on as normal
+        store.insert(b"b", b"2").unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+}
@@ -0,0 +1,143 @@
+//! every data file starts with a small header of its own: a magic number, so we can tell the file is ours,
+//! followed by the version of the record format inside it
+//! that gives us room to change the layout of records later on - a build that doesn't know a version refuses to touch it,
+//! instead of misreading it as garbage and "recovering" it away
+//!
+//! files written before the header existed start straight with a record, open() refuses those too and points at migrate()
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{error, fmt};
+
+use crate::{ActionKV, Storage};
+
+pub(crate) const MAGIC: [u8; 4] = *b"AKVD";
+/// bump whenever the on-disk record layout changes in a way older builds can't read
+pub(crate) const VERSION: u32 = 1;
+/// magic + version, 4 bytes each - records start right after it
+pub(crate) const FILE_HEADER_LEN: u64 = 8;
+
+/// why open() won't have anything to do with a data file
+/// handed back wrapped in an io::Error of kind InvalidData - grab it with `e.get_ref().and_then(|e| e.downcast_ref::<FormatError>())`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FormatError {
+    /// the file was written by a newer (or just different) version of libactionkv
+    UnsupportedVersion { path: PathBuf, version: u32 },
+    /// the file was written before data files had a header - ActionKV::migrate() upgrades it
+    Legacy { path: PathBuf },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::UnsupportedVersion { path, version } => write!(
+                f,
+                "{} uses format version {}, but this build only understands version {}",
+                path.display(), version, VERSION
+            ),
+            FormatError::Legacy { path } => write!(
+                f,
+                "{} was written by an older libactionkv without a file header - run ActionKV::migrate() on the store first",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl error::Error for FormatError {}
+
+impl From<FormatError> for io::Error {
+    fn from(e: FormatError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// what the start of a data file says about it
+enum Found {
+    Current,
+    /// nothing there yet, or a header that got cut short while the file was being created - either way no records
+    Empty,
+    UnsupportedVersion(u32),
+    Legacy,
+}
+
+pub(crate) fn header() -> [u8; FILE_HEADER_LEN as usize] {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4..].copy_from_slice(&VERSION.to_le_bytes());
+    header
+}
+
+fn inspect(f: &dyn Storage) -> io::Result<Found> {
+    let mut start = [0; FILE_HEADER_LEN as usize];
+    let mut len = 0;
+    while len < start.len() {
+        match f.read_at(&mut start[len..], len as u64)? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    let start = &start[..len];
+
+    if start.len() < FILE_HEADER_LEN as usize {
+        // a legacy file this short can't hold a whole record either, but it's not ours to throw away
+        return Ok(if header().starts_with(start) { Found::Empty } else { Found::Legacy });
+    }
+    if start[..4] != MAGIC {
+        return Ok(Found::Legacy);
+    }
+    let version = u32::from_le_bytes([start[4], start[5], start[6], start[7]]);
+    Ok(if version == VERSION { Found::Current } else { Found::UnsupportedVersion(version) })
+}
+
+/// called on every data file as it's opened (a file has to be readable and in append mode)
+/// a new file gets its header written, an existing one has to carry the header of the version we understand
+pub(crate) fn prepare(f: &dyn Storage, path: &Path) -> io::Result<()> {
+    match inspect(f)? {
+        Found::Current => Ok(()),
+        Found::Empty => {
+            f.truncate(0)?;
+            f.append(&header())
+        },
+        Found::UnsupportedVersion(version) => Err(FormatError::UnsupportedVersion { path: path.to_path_buf(), version }.into()),
+        Found::Legacy => Err(FormatError::Legacy { path: path.to_path_buf() }.into()),
+    }
+}
+
+/// prepare() for a file we mustn't write to - one without a header yet is as good as empty
+pub(crate) fn check(f: &dyn Storage, path: &Path) -> io::Result<()> {
+    match inspect(f)? {
+        Found::Current | Found::Empty => Ok(()),
+        Found::UnsupportedVersion(version) => Err(FormatError::UnsupportedVersion { path: path.to_path_buf(), version }.into()),
+        Found::Legacy => Err(FormatError::Legacy { path: path.to_path_buf() }.into()),
+    }
+}
+
+/// whether upgrade() has anything to do for the file at path
+pub(crate) fn is_legacy(path: &Path) -> io::Result<bool> {
+    match inspect(&File::open(path)?)? {
+        Found::Legacy => Ok(true),
+        Found::UnsupportedVersion(version) => Err(FormatError::UnsupportedVersion { path: path.to_path_buf(), version }.into()),
+        Found::Current | Found::Empty => Ok(false),
+    }
+}
+
+/// rewrites a legacy file with a header in front of its records
+/// the records themselves haven't changed, so they're copied over byte for byte
+/// same temp file + rename dance as compact(), a crash leaves either the old file or the new one behind
+pub(crate) fn upgrade(path: &Path) -> io::Result<()> {
+    let tmp_path = ActionKV::sibling_path(path, "migrate");
+    let mut tmp = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    tmp.write_all(&header())?;
+    io::copy(&mut File::open(path)?, &mut tmp)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    std::fs::rename(&tmp_path, path)?;
+    ActionKV::sync_parent_dir(path)
+}
@@ -0,0 +1,42 @@
+//! the compaction generation: a number that changes every time compact() or restore() rewrites the log
+//! a LogOffset carries the generation it was handed out in, so one that's gone stale can be told apart from one that's still good -
+//! after a compaction the same segment and offset can point into the middle of some other record entirely
+//!
+//! a store kept in files keeps it next to the data as decimal text, eg dbs/store.generation -
+//! it has to outlive the process, otherwise nobody could pick up where they left off after a restart
+//! it's written (and synced) before the new data is swapped in, so a crash in between can only make offsets look stale that weren't
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{now_millis, ActionKV};
+
+/// the generation a store starts out in - going by the clock rather than starting at 0 means a store
+/// that got deleted and made again doesn't hand out generations the old one already did
+pub(crate) fn first() -> u64 {
+    now_millis()
+}
+
+/// the generation after current
+pub(crate) fn next(current: u64) -> u64 {
+    first().max(current + 1)
+}
+
+/// None if there's no generation file (yet)
+pub(crate) fn read(path: &Path) -> io::Result<Option<u64>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    text.trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} doesn't hold a generation", path.display())))
+}
+
+/// never half written, see ActionKV::write_atomically()
+pub(crate) fn write(path: &Path, generation: u64) -> io::Result<()> {
+    ActionKV::write_atomically(path, format!("{}\n", generation).as_bytes())
+}
@@ -0,0 +1,118 @@
+//! hint files let load() rebuild the index without reading (and checksumming) every value in the data file
+//! same idea as in Bitcask, see section 4 of https://riak.com/assets/bitcask-intro.pdf
+//!
+//! layout, all LittleEndian:
+//! - MAGIC, so hints written in an older layout are ignored rather than misread
+//! - covered_segment (u32) and covered_len (u64): how much of the data the hint describes,
+//!   anything after that point in covered_segment, or in any later segment, still has to be scanned
+//! - for every live key: key_len (u32), segment (u32), offset (u64), len (u64), key
+//! - crc32 of everything above (u32), so a half written hint is ignored rather than trusted
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crc::crc32::{self, Hasher32};
+
+use crate::{ActionKV, ByteString, Position};
+
+const MAGIC: &[u8; 4] = b"AKVH";
+
+pub(crate) struct Hint {
+    pub(crate) covered_segment: u32,
+    pub(crate) covered_len: u64,
+    pub(crate) entries: Vec<(ByteString, Position)>,
+}
+
+/// passes writes through to w while keeping a running crc32 of them
+struct ChecksumWriter<W> {
+    w: W,
+    digest: crc32::Digest,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.w.write(buf)?;
+        self.digest.write(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// writes the hint to a temp file and renames it into place, so there's never a half written hint under the real name
+pub(crate) fn write<I>(path: &Path, covered_segment: u32, covered_len: u64, entries: I) -> io::Result<()>
+where
+    I: Iterator<Item = io::Result<(ByteString, Position)>>,
+{
+    let tmp_path = path.with_extension("hint-tmp");
+    let mut w = ChecksumWriter {
+        w: BufWriter::new(File::create(&tmp_path)?),
+        digest: crc32::Digest::new(crc32::IEEE),
+    };
+
+    w.write_all(MAGIC)?;
+    w.write_u32::<LittleEndian>(covered_segment)?;
+    w.write_u64::<LittleEndian>(covered_len)?;
+    for entry in entries {
+        let (key, position) = entry?;
+        w.write_u32::<LittleEndian>(key.len() as u32)?;
+        w.write_u32::<LittleEndian>(position.segment)?;
+        w.write_u64::<LittleEndian>(position.offset)?;
+        w.write_u64::<LittleEndian>(position.len)?;
+        w.write_all(&key)?;
+    }
+
+    let checksum = w.digest.sum32();
+    let mut f = w.w;
+    f.write_u32::<LittleEndian>(checksum)?;
+    let f = f.into_inner().map_err(|e| e.into_error())?;
+    f.sync_all()?;
+    drop(f);
+
+    fs::rename(&tmp_path, path)?;
+    ActionKV::sync_parent_dir(path)
+}
+
+/// None if there is no hint, or if it's damaged - either way the caller falls back to scanning all the data
+pub(crate) fn read(path: &Path) -> io::Result<Option<Hint>> {
+    let mut buf = vec![];
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut buf)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    if buf.len() < MAGIC.len() + 4 || !buf.starts_with(MAGIC) {
+        return Ok(None);
+    }
+    let (body, saved_checksum) = buf.split_at(buf.len() - 4);
+    if crc32::checksum_ieee(body) != Cursor::new(saved_checksum).read_u32::<LittleEndian>()? {
+        return Ok(None);
+    }
+
+    // the checksum matched, so running out of bytes from here on means the hint was written wrong - treat it as missing too
+    parse(body).or(Ok(None))
+}
+
+fn parse(body: &[u8]) -> io::Result<Option<Hint>> {
+    let mut r = Cursor::new(body);
+    r.set_position(MAGIC.len() as u64);
+    let covered_segment = r.read_u32::<LittleEndian>()?;
+    let covered_len = r.read_u64::<LittleEndian>()?;
+
+    let mut entries = vec![];
+    while (r.position() as usize) < body.len() {
+        let key_len = r.read_u32::<LittleEndian>()?;
+        let segment = r.read_u32::<LittleEndian>()?;
+        let offset = r.read_u64::<LittleEndian>()?;
+        let len = r.read_u64::<LittleEndian>()?;
+        let mut key = vec![0; key_len as usize];
+        r.read_exact(&mut key)?;
+        entries.push((key, Position { segment, offset, len }));
+    }
+
+    Ok(Some(Hint { covered_segment, covered_len, entries }))
+}
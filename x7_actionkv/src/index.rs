@@ -0,0 +1,374 @@
+//! the in-memory index: which key lives at which Position
+//! a HashMap is the fastest for plain lookups, a BTreeMap keeps the keys sorted so they can be walked in order,
+//! and Fingerprints keep no keys at all - for key sets too big to hold in memory
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hasher;
+use std::io;
+use std::ops::Bound;
+
+use crate::{ByteStr, ByteString, Position};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexKind {
+    #[default]
+    Hash,
+    /// needed for cheap range() and scan_prefix() - with a hash index those have to sort the matching keys first
+    Ordered,
+    /// only keeps a 64 bit hash of every key in memory (plus its position), about 40 bytes a key however long the keys are
+    /// the price is a read from disk to check the key on every lookup, and keys(), range() and friends
+    /// have to read every key back from disk - there's also no hint file, so load() always reads the whole store
+    Fingerprint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Index {
+    Hash(HashMap<ByteString, Position>),
+    Ordered(BTreeMap<ByteString, Position>),
+    Fingerprint(Fingerprints),
+}
+
+/// reads the key of the record at a position back from disk - how a fingerprint index tells keys apart
+pub(crate) type ReadKey<'a> = &'a dyn Fn(Position) -> io::Result<ByteString>;
+
+/// keyed by a 64 bit hash of the key
+/// two keys sharing a hash is vanishingly rare, but has to work - those end up in shared, and the disk decides which is which
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Fingerprints {
+    single: HashMap<u64, Position>,
+    shared: HashMap<u64, Vec<Position>>,
+}
+
+fn fingerprint(key: &ByteStr) -> u64 {
+    // the same hash every run isn't needed (it never leaves memory), but it doesn't hurt either
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
+
+impl Fingerprints {
+    fn get(&self, key: &ByteStr, read_key: ReadKey) -> io::Result<Option<Position>> {
+        let hash = fingerprint(key);
+        if let Some(position) = self.single.get(&hash) {
+            return Ok(if read_key(*position)? == key { Some(*position) } else { None });
+        }
+        for position in self.shared.get(&hash).into_iter().flatten() {
+            if read_key(*position)? == key {
+                return Ok(Some(*position));
+            }
+        }
+        Ok(None)
+    }
+
+    fn insert(&mut self, key: &ByteStr, position: Position, read_key: ReadKey) -> io::Result<()> {
+        let hash = fingerprint(key);
+        if let Some(existing) = self.single.get_mut(&hash) {
+            if read_key(*existing)? == key {
+                *existing = position;
+            } else {
+                // a different key with the same hash - from now on both have to be checked on disk
+                let existing = self.single.remove(&hash).unwrap();
+                self.shared.insert(hash, vec![existing, position]);
+            }
+            return Ok(());
+        }
+        if let Some(positions) = self.shared.get_mut(&hash) {
+            for existing in positions.iter_mut() {
+                if read_key(*existing)? == key {
+                    *existing = position;
+                    return Ok(());
+                }
+            }
+            positions.push(position);
+            return Ok(());
+        }
+        self.single.insert(hash, position);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &ByteStr, read_key: ReadKey) -> io::Result<()> {
+        let hash = fingerprint(key);
+        if let Some(existing) = self.single.get(&hash) {
+            if read_key(*existing)? == key {
+                self.single.remove(&hash);
+            }
+            return Ok(());
+        }
+        if let Some(positions) = self.shared.get_mut(&hash) {
+            let mut found = None;
+            for (i, existing) in positions.iter().enumerate() {
+                if read_key(*existing)? == key {
+                    found = Some(i);
+                    break;
+                }
+            }
+            if let Some(i) = found {
+                positions.remove(i);
+            }
+            if positions.len() == 1 {
+                self.single.insert(hash, positions[0]);
+                self.shared.remove(&hash);
+            }
+        }
+        Ok(())
+    }
+
+    fn contains_position(&self, key: &ByteStr, position: Position) -> bool {
+        let hash = fingerprint(key);
+        self.single.get(&hash) == Some(&position) || self.shared.get(&hash).is_some_and(|positions| positions.contains(&position))
+    }
+
+    fn positions(&self) -> impl Iterator<Item = &Position> {
+        self.single.values().chain(self.shared.values().flatten())
+    }
+
+    fn positions_mut(&mut self) -> impl Iterator<Item = &mut Position> {
+        self.single.values_mut().chain(self.shared.values_mut().flatten())
+    }
+}
+
+impl Index {
+    pub fn new(kind: IndexKind) -> Self {
+        match kind {
+            IndexKind::Hash => Index::Hash(HashMap::new()),
+            IndexKind::Ordered => Index::Ordered(BTreeMap::new()),
+            IndexKind::Fingerprint => Index::Fingerprint(Fingerprints::default()),
+        }
+    }
+
+    pub fn kind(&self) -> IndexKind {
+        match self {
+            Index::Hash(_) => IndexKind::Hash,
+            Index::Ordered(_) => IndexKind::Ordered,
+            Index::Fingerprint(_) => IndexKind::Fingerprint,
+        }
+    }
+
+    /// whether the full keys are in memory - the other kinds of index can do without read_key
+    pub(crate) fn holds_keys(&self) -> bool {
+        !matches!(self, Index::Fingerprint(_))
+    }
+
+    pub(crate) fn get(&self, key: &ByteStr, read_key: ReadKey) -> io::Result<Option<Position>> {
+        match self {
+            Index::Hash(map) => Ok(map.get(key).copied()),
+            Index::Ordered(map) => Ok(map.get(key).copied()),
+            Index::Fingerprint(fingerprints) => fingerprints.get(key, read_key),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, key: ByteString, position: Position, read_key: ReadKey) -> io::Result<()> {
+        match self {
+            Index::Hash(map) => {
+                map.insert(key, position);
+            },
+            Index::Ordered(map) => {
+                map.insert(key, position);
+            },
+            Index::Fingerprint(fingerprints) => fingerprints.insert(&key, position, read_key)?,
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, key: &ByteStr, read_key: ReadKey) -> io::Result<()> {
+        match self {
+            Index::Hash(map) => {
+                map.remove(key);
+            },
+            Index::Ordered(map) => {
+                map.remove(key);
+            },
+            Index::Fingerprint(fingerprints) => fingerprints.remove(key, read_key)?,
+        }
+        Ok(())
+    }
+
+    /// whether key's current value is the record at position - no need to go to disk for this one,
+    /// the caller has just read key from there
+    pub(crate) fn points_at(&self, key: &ByteStr, position: Position) -> bool {
+        match self {
+            Index::Hash(map) => map.get(key) == Some(&position),
+            Index::Ordered(map) => map.get(key) == Some(&position),
+            Index::Fingerprint(fingerprints) => fingerprints.contains_position(key, position),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Index::Hash(map) => map.len(),
+            Index::Ordered(map) => map.len(),
+            Index::Fingerprint(fingerprints) => fingerprints.single.len() + fingerprints.shared.values().map(Vec::len).sum::<usize>(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&mut self) {
+        match self {
+            Index::Hash(map) => map.clear(),
+            Index::Ordered(map) => map.clear(),
+            Index::Fingerprint(fingerprints) => *fingerprints = Fingerprints::default(),
+        }
+    }
+
+    /// every (key, position), in key order for an ordered index and in no particular order otherwise
+    /// a fingerprint index reads each key back from disk as it gets to it
+    pub(crate) fn entries<'a>(&'a self, read_key: ReadKey<'a>) -> Box<dyn Iterator<Item = io::Result<(ByteString, Position)>> + 'a> {
+        match self {
+            Index::Hash(map) => Box::new(map.iter().map(|(key, position)| Ok((key.clone(), *position)))),
+            Index::Ordered(map) => Box::new(map.iter().map(|(key, position)| Ok((key.clone(), *position)))),
+            Index::Fingerprint(fingerprints) => Box::new(fingerprints.positions().map(move |position| Ok((read_key(*position)?, *position)))),
+        }
+    }
+
+    /// where every live record is, in no particular order
+    pub(crate) fn positions(&self) -> Box<dyn Iterator<Item = &Position> + '_> {
+        match self {
+            Index::Hash(map) => Box::new(map.values()),
+            Index::Ordered(map) => Box::new(map.values()),
+            Index::Fingerprint(fingerprints) => Box::new(fingerprints.positions()),
+        }
+    }
+
+    /// points everything at moved[i].0 to moved[i].1 instead, and forgets about anything at a position in dropped
+    /// both have to be sorted by old position - that's how compact() reads them anyway - so no keys are needed
+    pub(crate) fn relocate(&mut self, moved: &[(Position, Position)], dropped: &[Position]) {
+        let key = |position: &Position| (position.segment, position.offset);
+        let is_dropped = |position: &Position| dropped.binary_search_by_key(&key(position), key).is_ok();
+        match self {
+            Index::Hash(map) => map.retain(|_, position| !is_dropped(position)),
+            Index::Ordered(map) => map.retain(|_, position| !is_dropped(position)),
+            Index::Fingerprint(fingerprints) => {
+                fingerprints.single.retain(|_, position| !is_dropped(position));
+                for positions in fingerprints.shared.values_mut() {
+                    positions.retain(|position| !is_dropped(position));
+                }
+                let single: Vec<(u64, Position)> = fingerprints.shared
+                    .iter()
+                    .filter(|(_, positions)| positions.len() == 1)
+                    .map(|(hash, positions)| (*hash, positions[0]))
+                    .collect();
+                fingerprints.shared.retain(|_, positions| positions.len() > 1);
+                fingerprints.single.extend(single);
+            },
+        }
+
+        let positions: Box<dyn Iterator<Item = &mut Position>> = match self {
+            Index::Hash(map) => Box::new(map.values_mut()),
+            Index::Ordered(map) => Box::new(map.values_mut()),
+            Index::Fingerprint(fingerprints) => Box::new(fingerprints.positions_mut()),
+        };
+        for position in positions {
+            if let Ok(i) = moved.binary_search_by_key(&key(position), |(old, _)| key(old)) {
+                *position = moved[i].1;
+            }
+        }
+    }
+
+    /// the first key (in order) that falls within (start, end)
+    /// only makes sense for an ordered index - for the others that would mean looking at every key, every time
+    pub(crate) fn first_in(&self, start: Bound<&ByteStr>, end: Bound<&ByteStr>) -> Option<(ByteString, Position)> {
+        match self {
+            Index::Hash(_) | Index::Fingerprint(_) => None,
+            Index::Ordered(map) => map
+                .range::<ByteStr, _>((start, end))
+                .next()
+                .map(|(key, position)| (key.clone(), *position)),
+        }
+    }
+
+    /// fills the index from a hint - every key in there is different, so a fingerprint index can take them without checking
+    pub(crate) fn extend_from_hint(&mut self, entries: Vec<(ByteString, Position)>) {
+        match self {
+            Index::Hash(map) => map.extend(entries),
+            Index::Ordered(map) => map.extend(entries),
+            Index::Fingerprint(fingerprints) => {
+                for (key, position) in entries {
+                    // can't fail - with no key read from disk, every key goes in as a new one
+                    let _ = fingerprints.insert(&key, position, &|_| Ok(vec![]));
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // keys "on disk", one per offset - what read_key hands back
+    fn disk<'a>(keys: &'a [&'a str]) -> impl Fn(Position) -> io::Result<ByteString> + 'a {
+        move |position: Position| Ok(keys[position.offset as usize].as_bytes().to_vec())
+    }
+
+    fn at(offset: u64) -> Position {
+        Position { segment: 0, offset, len: 1 }
+    }
+
+    // a real collision can't be made to order, so "b" is filed under the hash of "a" by hand -
+    // as far as the index can tell, that's exactly what two keys sharing a hash look like
+    fn colliding() -> (Fingerprints, u64) {
+        let hash = fingerprint(b"a");
+        let mut fingerprints = Fingerprints::default();
+        fingerprints.single.insert(hash, at(1));
+        (fingerprints, hash)
+    }
+
+    #[test]
+    fn a_second_key_with_the_same_hash_is_shared_and_both_stay_apart() {
+        let keys = ["a", "b", "a"];
+        let read_key = disk(&keys);
+        let (mut fingerprints, hash) = colliding();
+
+        fingerprints.insert(b"a", at(0), &read_key).unwrap();
+        assert!(fingerprints.single.is_empty());
+        assert_eq!(fingerprints.shared[&hash], vec![at(1), at(0)]);
+        assert_eq!(fingerprints.get(b"a", &read_key).unwrap(), Some(at(0)));
+
+        // overwriting "a" only touches its own slot
+        fingerprints.insert(b"a", at(2), &read_key).unwrap();
+        assert_eq!(fingerprints.shared[&hash], vec![at(1), at(2)]);
+        assert_eq!(fingerprints.get(b"a", &read_key).unwrap(), Some(at(2)));
+        assert_eq!(fingerprints.positions().count(), 2);
+    }
+
+    #[test]
+    fn removing_one_of_two_shared_keys_leaves_the_other_single() {
+        let keys = ["a", "b"];
+        let read_key = disk(&keys);
+        let (mut fingerprints, hash) = colliding();
+        fingerprints.insert(b"a", at(0), &read_key).unwrap();
+
+        fingerprints.remove(b"a", &read_key).unwrap();
+        assert!(fingerprints.shared.is_empty());
+        assert_eq!(fingerprints.single.get(&hash), Some(&at(1)));
+        assert_eq!(fingerprints.get(b"a", &read_key).unwrap(), None);
+
+        // a key that was never there leaves the other one alone
+        fingerprints.remove(b"a", &read_key).unwrap();
+        assert_eq!(fingerprints.single.get(&hash), Some(&at(1)));
+    }
+
+    #[test]
+    fn relocate_moves_shared_positions_and_collapses_what_is_left() {
+        let keys = ["a", "b"];
+        let read_key = disk(&keys);
+        let (mut fingerprints, hash) = colliding();
+        fingerprints.insert(b"a", at(0), &read_key).unwrap();
+        let mut index = Index::Fingerprint(fingerprints);
+
+        let moved = Position { segment: 1, offset: 8, len: 1 };
+        index.relocate(&[(at(1), moved)], &[at(0)]);
+        match index {
+            Index::Fingerprint(fingerprints) => {
+                assert!(fingerprints.shared.is_empty());
+                assert_eq!(fingerprints.single.get(&hash), Some(&moved));
+                assert!(fingerprints.contains_position(b"a", moved));
+            },
+            _ => unreachable!(),
+        }
+    }
+}
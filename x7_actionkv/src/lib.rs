@@ -1,89 +1,761 @@
 use serde::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
-use std::collections::HashMap;
-use std::path::Path;
-use std::io;
-use std::io::{BufReader, Seek, SeekFrom, Read, BufWriter, Write};
+use std::fs::{self, File, OpenOptions};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::{RangeBounds, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{error, fmt, io};
+use std::io::{BufReader, Read, BufWriter, Write};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use crc::{crc32};
+use crc::crc32::{self, Hasher32};
+use memmap2::Mmap;
+
+mod batch;
+mod cache;
+mod compression;
+mod conditional;
+mod durability;
+mod format;
+mod generation;
+mod hint;
+mod index;
+mod lock;
+mod replication;
+mod scan;
+mod secondary;
+mod segment;
+mod snapshot;
+mod storage;
+mod typed;
+mod value;
+
+pub use batch::WriteBatch;
+pub use cache::CacheStats;
+use cache::Cache;
+use batch::{Batches, Framed};
+pub use compression::Compression;
+pub use durability::Durability;
+use format::FILE_HEADER_LEN;
+pub use format::FormatError;
+use durability::Syncer;
+pub use index::{Fingerprints, Index, IndexKind};
+pub use replication::{lead, Change, Compacted, Event, LogOffset, Subscription};
+use replication::Tail;
+pub use scan::{Iter, Range};
+pub use typed::{Bincode, Codec, TypedKV};
+use secondary::Secondaries;
+use segment::Segment;
+pub use storage::{MemoryStorage, Storage};
+pub use value::Value;
 
 type ByteString = Vec<u8>; //like String but not guaranteed to be utf-8
 type ByteStr = [u8]; //like &str but not guaranteed to be utf-8
 
 // for an example of where invalid utf-8 causes String to error see this -> https://people.gnome.org/~federico/blog/correctness-in-rust-reading-strings.html
 
+// the top byte of the on-disk key_len is used for per-record flags, which leaves 24 bits (16 MiB) for the key itself
+// files written before flags existed always have a zero top byte there, so they keep loading unchanged
+const KEY_LEN_MASK: u32 = 0x00FF_FFFF;
+const FLAGS_SHIFT: u32 = 24;
+
+/// the record marks its key as deleted - it carries no value
+const FLAG_TOMBSTONE: u8 = 0b0000_0001;
+/// the record is part of a WriteBatch and only counts once the batch's commit marker has been seen
+const FLAG_BATCHED: u8 = 0b0000_0010;
+/// marker records framing a WriteBatch - empty key, number of writes in the batch as the value
+const FLAG_BATCH_BEGIN: u8 = 0b0000_0100;
+const FLAG_BATCH_COMMIT: u8 = 0b0000_1000;
+/// the record stops counting at some point - when exactly follows the header, see EXPIRY_LEN
+const FLAG_EXPIRES: u8 = 0b0001_0000;
+/// the value is stored compressed - the lengths and checksum describe the compressed bytes
+const FLAG_COMPRESSED: u8 = 0b0010_0000;
+
+/// checksum + key len + val len, 4 bytes each
+const HEADER_LEN: u64 = 12;
+/// records with FLAG_EXPIRES carry their expiry time right after the header, as milliseconds since the unix epoch
+const EXPIRY_LEN: u64 = 8;
+
+/// a corrupted length in a header could claim gigabytes - don't trust it for preallocation
+const MAX_PREALLOC: u32 = 64 * 1024;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
 }
 
+/// what went wrong with a record on disk
+/// load() and get() hand these back wrapped in an io::Error - grab it with `e.get_ref().and_then(|e| e.downcast_ref::<Corruption>())`
+/// segment is always 0 for a store kept in a single file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Corruption {
+    /// the record at this offset doesn't match its checksum
+    ChecksumMismatch { segment: u32, offset: u64 },
+    /// the file ends part way through the record at this offset - typically a write cut short by a crash
+    TornRecord { segment: u32, offset: u64 },
+}
+
+impl Corruption {
+    /// process_record() doesn't know where it's reading from, so the caller attaches the location afterwards
+    fn locate(e: io::Error, segment: u32, offset: u64) -> io::Error {
+        let (kind, corruption) = match e.kind() {
+            io::ErrorKind::InvalidData => (io::ErrorKind::InvalidData, Corruption::ChecksumMismatch { segment, offset }),
+            io::ErrorKind::UnexpectedEof => (io::ErrorKind::UnexpectedEof, Corruption::TornRecord { segment, offset }),
+            _ => return e,
+        };
+        io::Error::new(kind, corruption)
+    }
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::ChecksumMismatch { segment, offset } => {
+                write!(f, "checksums don't match for record at offset {} in segment {}", offset, segment)
+            },
+            Corruption::TornRecord { segment, offset } => {
+                write!(f, "record at offset {} in segment {} is cut short", offset, segment)
+            },
+        }
+    }
+}
+
+impl error::Error for Corruption {}
+
+/// how the space on disk is being used, see ActionKV::stats()
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// every record on disk - old versions, tombstones and batch markers included
+    pub records: u64,
+    /// records the index points at, ie one per key
+    pub live_records: u64,
+    /// bytes taken up by the live records, headers included
+    pub live_bytes: u64,
+    /// bytes taken up by everything else - roughly what compact() would give back
+    pub dead_bytes: u64,
+    pub segments: usize,
+}
+
+/// what recover() had to throw away to get the store loading again
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    /// every record skipped because of a bad checksum
+    /// positions refer to the files as they were before recovery - they get rewritten afterwards
+    pub skipped: Vec<Position>,
+    /// bytes cut off the end of files because they didn't add up to a whole record
+    pub truncated_bytes: u64,
+    /// where the raw bytes of the skipped records were copied to, if there were any
+    pub quarantine: Option<PathBuf>,
+}
+
+impl RecoveryReport {
+    pub fn is_clean(&self) -> bool {
+        self.skipped.is_empty() && self.truncated_bytes == 0
+    }
+}
+
+/// the start of every record: the fixed 12 bytes, plus the expiry if the flags say there is one
+/// the only place that knows how the flags are packed into the key length
+#[derive(Debug, Clone, Copy)]
+struct RecordHeader {
+    checksum: u32,
+    flags: u8,
+    key_len: u32,
+    val_len: u32,
+    expires_at: Option<u64>,
+}
+
+impl RecordHeader {
+    fn read<R: Read>(f: &mut R) -> io::Result<Self> {
+        // remember we're passing in a stream of bytes
+        // but it's important which way bytes are formatted - Little or Big endian
+        // here we ensure they're read as LittleEndian, plucking the first 3x 4 bytes = 12 bytes (header in Bitcask)
+        let checksum = f.read_u32::<LittleEndian>()?;
+        let raw_key_len = f.read_u32::<LittleEndian>()?;
+        let val_len = f.read_u32::<LittleEndian>()?;
+        // split the flags byte off the key length
+        let flags = (raw_key_len >> FLAGS_SHIFT) as u8;
+        let key_len = raw_key_len & KEY_LEN_MASK;
+        let expires_at = if flags & FLAG_EXPIRES != 0 { Some(f.read_u64::<LittleEndian>()?) } else { None };
+        Ok(RecordHeader { checksum, flags, key_len, val_len, expires_at })
+    }
+
+    /// how many bytes the header itself takes up
+    fn len(&self) -> u64 {
+        ActionKV::header_len(self.flags)
+    }
+
+    /// key and value - u64 so that a garbage header can't overflow the addition
+    fn data_len(&self) -> u64 {
+        self.key_len as u64 + self.val_len as u64
+    }
+
+    /// the whole record, header included
+    fn record_len(&self) -> u64 {
+        self.len() + self.data_len()
+    }
+}
+
+/// a KeyValuePair plus whatever the record header had to say about it
+#[derive(Debug)]
+struct Record {
+    kv: KeyValuePair,
+    flags: u8,
+    /// how many bytes the record takes up on disk, header included
+    len: u64,
+    /// milliseconds since the unix epoch, for records written by insert_with_ttl()
+    expires_at: Option<u64>,
+}
+
+impl Record {
+    fn is_tombstone(&self) -> bool {
+        self.flags & FLAG_TOMBSTONE != 0
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        is_expired(self.expires_at, now)
+    }
+
+    fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// the number of writes a batch marker announces
+    fn batch_count(&self) -> u32 {
+        let mut count = [0; 4];
+        let len = self.kv.value.len().min(4);
+        count[..len].copy_from_slice(&self.kv.value[..len]);
+        u32::from_le_bytes(count)
+    }
+}
+
+/// where a record sits on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// which segment file the record is in - always 0 for a store kept in a single file
+    pub segment: u32,
+    /// where the record starts
+    pub offset: u64,
+    /// how many bytes it takes up, header included
+    pub len: u64,
+}
+
+/// knobs for ActionKV::open_with()
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// None keeps the whole store in the single file at `path`
+    /// Some(max) treats `path` as a directory of segment files, starting a new one whenever the current one would grow past max bytes
+    pub max_segment_size: Option<u64>,
+    /// how hard we try to get writes onto the disk before returning - see Durability
+    pub durability: Durability,
+    /// hash by default - pick Ordered if you need range() or scan_prefix() on a big store,
+    /// Fingerprint if the keys themselves won't fit in memory
+    pub index: IndexKind,
+    /// read values through a memory map of the segment files instead of copying them out with a read() per get
+    /// get_value() then hands back values that point straight into the map
+    pub mmap: bool,
+    /// whether values get compressed on their way to disk - see Compression
+    pub compression: Compression,
+    /// only read from the store - anything that would write to it fails with PermissionDenied
+    /// read-only opens share the store's lock, so they can run side by side (but not alongside a writer)
+    pub read_only: bool,
+    /// keep up to this many bytes of recently read keys and values in memory - 0 (the default) means no cache
+    pub cache_size: u64,
+}
+
+/// where a record's key and value sit within its segment's map
+struct MappedRecord {
+    map: Arc<Mmap>,
+    key: std::ops::Range<usize>,
+    /// still compressed, if this is set
+    value: std::ops::Range<usize>,
+    expires_at: Option<u64>,
+    compressed: bool,
+}
+
+/// (key, value, flags, expires_at) - what append() needs to know to write a record
+type NewRecord<'a> = (&'a ByteStr, &'a ByteStr, u8, Option<u64>);
+
+/// what compact() copied over
+/// both in the order the records were read, ie sorted by old position
+struct Copied {
+    /// where each record was, and where it ended up
+    moved: Vec<(Position, Position)>,
+    /// records left out because they've expired, with their keys (for the secondary indexes)
+    expired: Vec<(Position, ByteString)>,
+}
+
+/// milliseconds since the unix epoch - what record expiry times are measured in
+fn now_millis() -> u64 {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX)
+}
+
+fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+/// the parts of the store that change as it gets written to
+/// kept behind a single lock, so a reader can never pick up a position from the index
+/// and then find the segment it points into already swapped out by compact()
+#[derive(Debug)]
+struct State {
+    /// keyed by segment id, oldest first - the last one is the only one we ever append to
+    segments: BTreeMap<u32, Arc<Segment>>,
+    index: Index,
+    secondary: Secondaries,
+    /// bumped by every compact(), see generation.rs
+    generation: u64,
+}
+
+impl State {
+    fn active_id(&self) -> u32 {
+        // there's always at least one segment, open_with() makes sure of that
+        *self.segments.keys().next_back().unwrap()
+    }
+
+    fn active(&self) -> &Arc<Segment> {
+        &self.segments[&self.active_id()]
+    }
+
+    fn segment(&self, id: u32) -> io::Result<&Arc<Segment>> {
+        self.segments
+            .get(&id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no segment with id {}", id)))
+    }
+
+    /// where key's current value is - a fingerprint index may have to read a key or two from disk to tell
+    fn lookup(&self, key: &ByteStr) -> io::Result<Option<Position>> {
+        let segments = &self.segments;
+        self.index.get(key, &|position| ActionKV::key_at(segments, position))
+    }
+
+    /// points key at its new value - the value itself is only needed by the secondary indexes
+    fn index_insert(&mut self, key: ByteString, position: Position, value: &ByteStr) -> io::Result<()> {
+        self.secondary.insert(&key, value);
+        let segments = &self.segments;
+        self.index.insert(key, position, &|position| ActionKV::key_at(segments, position))
+    }
+
+    fn index_remove(&mut self, key: &ByteStr) -> io::Result<()> {
+        self.secondary.remove(key);
+        let segments = &self.segments;
+        self.index.remove(key, &|position| ActionKV::key_at(segments, position))
+    }
+}
+
+/// the store itself
+/// everything apart from open/load/recover/close takes &self, so it can be shared between threads in an Arc:
+/// any number of threads can read at the same time, while writes queue up and go in one at a time
 #[derive(Debug)]
 pub struct ActionKV {
-    f: File,
-    pub index: HashMap<ByteString, u64>,
+    /// either the data file, or the directory holding the segments - None for a store on some other Storage
+    path: Option<PathBuf>,
+    max_segment_size: Option<u64>,
+    mmap: bool,
+    compression: Compression,
+    read_only: bool,
+    state: RwLock<State>,
+    /// held for the whole of a write (but not while waiting for the sync), so only one writer is ever appending
+    writer: Mutex<()>,
+    syncer: Arc<Syncer>,
+    /// wakes up subscriptions waiting for something new to be written
+    tail: Tail,
+    cache: Cache,
+    /// never read - holding on to it is what keeps other processes out, see lock.rs
+    _lock: Option<File>,
 }
 
+/// proof that the caller is the one writer allowed to append right now
+type WriteGuard<'a> = MutexGuard<'a, ()>;
+
 impl ActionKV {
     pub fn open(path: &Path) -> io::Result<Self> {
-        // opens the file in append only mode
+        ActionKV::open_with(path, Options::default())
+    }
+
+    pub fn open_with(path: &Path, options: Options) -> io::Result<Self> {
+        options.durability.check()?;
+        let writable = !options.read_only;
+        let segmented = options.max_segment_size.is_some();
+        if segmented && writable {
+            fs::create_dir_all(path)?;
+        }
+        // before touching any data file - a new one gets its header written, and that's a write like any other
+        let lock = lock::acquire(&ActionKV::aux_path_of(path, segmented, "lock"), path, writable)?;
+
+        let mut segments = BTreeMap::new();
+        match options.max_segment_size {
+            None => {
+                // opens the file in append only mode
+                let f = ActionKV::open_data_file(path, writable)?;
+                segments.insert(0, Arc::new(Segment::new(0, Some(path.to_path_buf()), f)));
+            },
+            Some(_) => {
+                for (id, (first, segment_path)) in segment::list(path, writable)? {
+                    let f = ActionKV::open_data_file(&segment_path, writable)?;
+                    segments.insert(id, Arc::new(Segment::new(first, Some(segment_path), f)));
+                }
+                // brand new store - start off with an empty segment to write into
+                if segments.is_empty() {
+                    if !writable {
+                        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no segments in {}", path.display())));
+                    }
+                    let segment_path = path.join(segment::file_name(1, 1));
+                    let f = ActionKV::open_data_file(&segment_path, writable)?;
+                    segments.insert(1, Arc::new(Segment::new(1, Some(segment_path), f)));
+                }
+            },
+        }
+        let generation_path = ActionKV::aux_path_of(path, segmented, "generation");
+        let generation = match generation::read(&generation_path)? {
+            Some(generation) => generation,
+            // a read-only store can't make one up for good, and the offsets it hands out are never going to go stale anyway
+            None if !writable => 0,
+            None => {
+                let generation = generation::first();
+                generation::write(&generation_path, generation)?;
+                generation
+            },
+        };
+        // we hold on to the path so that compact() can swap fresh files in under the same names
+        Ok(ActionKV::assemble(Some(path.to_path_buf()), segments, generation, options, Some(lock)))
+    }
+
+    /// a store kept in storage instead of a file - load() it if there's anything in there already
+    /// always a single segment, so max_segment_size has to be None
+    pub fn with_storage<S: Storage + 'static>(storage: S, options: Options) -> io::Result<Self> {
+        options.durability.check()?;
+        if options.max_segment_size.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only a store kept in files can be split into segments"));
+        }
+        // there's no file name to blame in a FormatError
+        let name = Path::new("<storage>");
+        if options.read_only {
+            format::check(&storage, name)?;
+        } else {
+            format::prepare(&storage, name)?;
+        }
+        let mut segments = BTreeMap::new();
+        segments.insert(0, Arc::new(Segment::new(0, None, Arc::new(storage))));
+        Ok(ActionKV::assemble(None, segments, generation::first(), options, None))
+    }
+
+    /// a fresh, empty store that never touches the disk - gone once it's dropped
+    pub fn in_memory() -> Self {
+        ActionKV::with_storage(MemoryStorage::new(), Options::default()).expect("a new MemoryStorage can always be written to")
+    }
+
+    fn assemble(path: Option<PathBuf>, segments: BTreeMap<u32, Arc<Segment>>, generation: u64, options: Options, lock: Option<File>) -> Self {
+        // the syncer gets its own handle on the active segment, so its background thread (if any) doesn't need the store
+        let active = segments.values().next_back().expect("there's always at least one segment");
+        let syncer = Syncer::new(options.durability, Arc::clone(&active.f));
+        // creates an index in the form of a hashmap (or a btreemap, if the keys need to be kept in order)
+        let index = Index::new(options.index);
+        // only files can be mapped, anything else is read the ordinary way
+        let mmap = options.mmap && active.f.file().is_some();
+        Self {
+            path,
+            max_segment_size: options.max_segment_size,
+            mmap,
+            compression: options.compression,
+            read_only: options.read_only,
+            state: RwLock::new(State { segments, index, secondary: Secondaries::default(), generation }),
+            writer: Mutex::new(()),
+            syncer,
+            tail: Tail::default(),
+            cache: Cache::new(options.cache_size),
+            _lock: lock,
+        }
+    }
+
+    fn open_data_file(path: &Path, writable: bool) -> io::Result<Arc<dyn Storage>> {
+        if !writable {
+            let f = File::open(path)?;
+            format::check(&f, path)?;
+            return Ok(Arc::new(f));
+        }
+        // append implies write, so no need for .write(true)
         let f = OpenOptions::new()
             .read(true)
-            .write(true)
             .create(true)
             .append(true)
             .open(path)?;
-        // creates an index in the form of a hashmap
-        let index = HashMap::new();
-        Ok(Self{ f, index })
+        // a new file gets a header, an existing one has to have one we understand
+        format::prepare(&f, path)?;
+        Ok(Arc::new(f))
+    }
+
+    /// the error every write to a read-only store fails with
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "store was opened read-only"));
+        }
+        Ok(())
+    }
+
+    /// upgrades a store written before data files had a header - open() refuses those with FormatError::Legacy
+    /// path is whatever you'd pass to open(), a data file or a directory of segments
+    /// the store mustn't be open while this runs; returns how many files had to be rewritten, so 0 means it was up to date already
+    /// safe to run again if it gets interrupted - files that were already done are left alone
+    pub fn migrate(path: &Path) -> io::Result<usize> {
+        let segmented = path.is_dir();
+        let _lock = lock::acquire(&ActionKV::aux_path_of(path, segmented, "lock"), path, true)?;
+        let files: Vec<PathBuf> = if segmented {
+            segment::list(path, true)?.into_values().map(|(_, path)| path).collect()
+        } else {
+            vec![path.to_path_buf()]
+        };
+        let hint_path = ActionKV::aux_path_of(path, segmented, "hint");
+
+        let mut legacy = vec![];
+        for file in files {
+            if format::is_legacy(&file)? {
+                legacy.push(file);
+            }
+        }
+        if legacy.is_empty() {
+            return Ok(0);
+        }
+
+        // every offset in an old hint is off by the size of the header now, so it has to go before any file changes
+        ActionKV::remove_if_exists(&hint_path)?;
+        for file in &legacy {
+            format::upgrade(file)?;
+        }
+        Ok(legacy.len())
+    }
+
+    /// where files that belong to the store as a whole go, eg the hint
+    /// dbs/store -> dbs/store.hint for a single file store, dbs/store/store.hint for a segmented one
+    /// None if the store isn't kept in files, so there's nowhere to put them
+    fn aux_path(&self, extension: &str) -> Option<PathBuf> {
+        let path = self.path.as_ref()?;
+        Some(ActionKV::aux_path_of(path, self.max_segment_size.is_some(), extension))
+    }
+
+    /// aux_path() for a store that isn't open
+    pub(crate) fn aux_path_of(path: &Path, segmented: bool, extension: &str) -> PathBuf {
+        if segmented {
+            path.join(format!("store.{}", extension))
+        } else {
+            ActionKV::sibling_path(path, extension)
+        }
+    }
+
+    fn state(&self) -> RwLockReadGuard<'_, State> {
+        self.state.read().unwrap()
     }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, State> {
+        self.state.write().unwrap()
+    }
+
     /// populates the index with key-value pairs and where they sit in the file
     /// what if a kv appears twice: earlier and later in the file?
     /// later read will take precedence, which is the whole idea behind an append-only log-based data store
+    /// (with segments that means replaying them oldest to newest)
+    /// a damaged record makes this fail with a Corruption error - use recover() to get past it
+    ///
+    /// if close() or compact() left a hint file behind, the index is rebuilt from that instead,
+    /// and only the records appended after the hint was written need to be read from the data files
     pub fn load(&mut self) -> io::Result<()> {
+        // a fingerprint index would have to hold every key in the hint in memory to read it, which is what it's there to avoid
+        // and secondary indexes need the values, which the hint doesn't have
+        let use_hint = {
+            let state = self.state();
+            state.index.holds_keys() && state.secondary.is_empty()
+        };
+        let hint = match self.aux_path("hint") {
+            Some(hint_path) if use_hint => hint::read(&hint_path)?,
+            _ => None,
+        };
+        let state = self.state.get_mut().unwrap();
+        self.cache.clear();
+        let (start_segment, start_offset) = match hint {
+            Some(hint) if ActionKV::hint_matches(state, &hint)? => {
+                state.index.clear();
+                state.index.extend_from_hint(hint.entries);
+                (hint.covered_segment, hint.covered_len)
+            },
+            _ => (0, FILE_HEADER_LEN),
+        };
+
+        let mut report = RecoveryReport::default();
+        let ids: Vec<u32> = state.segments.keys().copied().filter(|id| *id >= start_segment).collect();
+        for id in ids {
+            let start = if id == start_segment { start_offset } else { FILE_HEADER_LEN };
+            self.scan(id, start, false, &mut report)?;
+        }
+        Ok(())
+    }
+
+    /// a hint is only any good if every file it points into is still there and at least as long as it remembers
+    /// otherwise it's left over from some other version of the store
+    fn hint_matches(state: &State, hint: &hint::Hint) -> io::Result<bool> {
+        match state.segments.get(&hint.covered_segment) {
+            Some(segment) if segment.len()? >= hint.covered_len => {},
+            _ => return Ok(false),
+        }
+        Ok(hint.entries.iter().all(|(_, position)| state.segments.contains_key(&position.segment)))
+    }
+
+    /// like load(), but instead of giving up on damaged records it:
+    /// - cuts a torn record off the end of a file (a crash half way through an insert leaves one behind)
+    /// - skips records with a bad checksum, copying their raw bytes into `<file>.quarantine` for a human to look at
+    ///
+    /// if anything was skipped the store is compacted afterwards, so a plain load() works on it again
+    /// note we trust the lengths in each header to find the next record - if a length itself is damaged
+    /// the rest of the file can't be told apart from a torn write and gets truncated
+    pub fn recover(&mut self) -> io::Result<RecoveryReport> {
+        self.check_writable()?;
+        // the hint can't be trusted to describe a damaged file, and we're reading every record anyway
+        if let Some(hint_path) = self.aux_path("hint") {
+            ActionKV::remove_if_exists(&hint_path)?;
+        }
+
+        self.cache.clear();
+        let mut report = RecoveryReport::default();
+        let ids: Vec<u32> = self.state.get_mut().unwrap().segments.keys().copied().collect();
+        for id in ids {
+            self.scan(id, FILE_HEADER_LEN, true, &mut report)?;
+        }
+
+        if !report.skipped.is_empty() {
+            self.compact()?;
+        }
+        Ok(report)
+    }
+
+    /// reads the records of one segment from start onwards into the index
+    fn scan(&mut self, id: u32, start: u64, recover: bool, report: &mut RecoveryReport) -> io::Result<()> {
+        let quarantine_path = self.aux_path("quarantine");
+        let state = self.state.get_mut().unwrap();
+        let segment = Arc::clone(&state.segments[&id]);
+        // knowing where the file ends lets us tell a clean end apart from a record that got cut short
+        let file_len = segment.len()?;
+        let mut torn_at = None;
+
         //performs large, infrequent reads on the underlying Read and maintains an in-memory buffer of the results.
-        let mut f = BufReader::new(&mut self.f);
+        let mut f = BufReader::new(segment.reader_at(start));
+        //every record tells us its own length, so from here on we can keep track of where we are without asking the file
+        let mut current_position = start;
+        let now = now_millis();
+        //records of a WriteBatch wait in here until we've seen its commit marker
+        let mut batches = Batches::new();
 
         loop {
-            //The Seek trait provides a cursor which can be moved within a stream of bytes.
-            //SeekFrom::Current Sets the offset to the current position plus the specified number of bytes.
-            let current_position = f.seek(SeekFrom::Current(0))?;
+            if current_position >= file_len {
+                break;
+            }
 
             //try to process the next key value from the current position
             //to actually process the record we're using an implementation of the Bitcask storage standard
             //it's nosql, slow, but guarantees it will never lose / compromise data
-            let maybe_kv = ActionKV::process_record(&mut f);
-            let kv = match maybe_kv {
-                Ok(kv) => kv,
+            let maybe_record = ActionKV::process_record(&mut f);
+            let record = match maybe_record {
+                Ok(record) => record,
                 Err(e) => {
                     match e.kind() {
-                        // if reach EOF we break out of the loop
-                        io::ErrorKind::UnexpectedEof => break,
+                        // we're not at the end yet, so this is a partial record - remember where it starts and stop
+                        io::ErrorKind::UnexpectedEof if recover => {
+                            torn_at = Some(current_position);
+                            break;
+                        },
+                        // bad checksum - process_record() still consumed the whole record, so f is already at the next one
+                        io::ErrorKind::InvalidData if recover => {
+                            let len = ActionKV::record_len_at(&segment, current_position)?;
+                            // a store that isn't kept in files has nowhere to put them, so its damaged records are simply dropped
+                            if let Some(quarantine_path) = &quarantine_path {
+                                let mut raw = vec![0; len as usize];
+                                segment.read_exact_at(&mut raw, current_position)?;
+
+                                let quarantine = report.quarantine.get_or_insert_with(|| quarantine_path.clone());
+                                OpenOptions::new().create(true).append(true).open(quarantine)?.write_all(&raw)?;
+                            }
+                            report.skipped.push(Position { segment: id, offset: current_position, len });
+                            current_position += len;
+                            //we can't tell whether the record we lost belonged to the open batch, so the batch can't be trusted
+                            batches.poison();
+                            continue;
+                        },
                         // for all other errors return the error itself
-                        _ => return Err(e),
+                        _ => return Err(Corruption::locate(e, id, current_position)),
                     }
                 },
             };
 
+            let position = Position { segment: id, offset: current_position, len: record.len };
+            current_position += record.len;
+
+            let batched = |record: Record| {
+                let op = if record.is_tombstone() { None } else { Some((position, record.kv.value)) };
+                (record.kv.key, op)
+            };
+            let record = match batches.next(record, position.offset, batched) {
+                Framed::Single(record) => record,
+                Framed::Committed(ops) => {
+                    for (key, op) in ops {
+                        match op {
+                            Some((position, value)) => state.index_insert(key, position, &value)?,
+                            None => state.index_remove(&key)?,
+                        }
+                    }
+                    continue;
+                },
+                Framed::Held => continue,
+            };
+
+            //a tombstone means the key was deleted after whatever came before it, so forget about it
+            //same goes for a value that has expired - whatever it replaced is gone as well
+            if record.is_tombstone() || record.is_expired(now) {
+                state.index_remove(&record.kv.key)?;
+                continue;
+            }
+
             //if kv processed successfully, insert it into the index so it can be quickly found later
-            self.index.insert(kv.key, current_position);
+            state.index_insert(record.kv.key, position, &record.kv.value)?;
+        }
+
+        if let Some(position) = torn_at {
+            segment.f.truncate(position)?;
+            report.truncated_bytes += file_len - position;
         }
 
         Ok(())
     }
 
+    /// the length of the record at offset, going by its header alone
+    fn record_len_at(segment: &Segment, offset: u64) -> io::Result<u64> {
+        Ok(RecordHeader::read(&mut segment.reader_at(offset))?.record_len())
+    }
+
+    /// the key of the record at position, without reading (or checking) the rest of it
+    fn key_at(segments: &BTreeMap<u32, Arc<Segment>>, position: Position) -> io::Result<ByteString> {
+        let segment = segments
+            .get(&position.segment)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no segment with id {}", position.segment)))?;
+        let header = RecordHeader::read(&mut segment.reader_at(position.offset))?;
+        let mut key = vec![0; header.key_len as usize];
+        segment.read_exact_at(&mut key, position.offset + header.len())?;
+        Ok(key)
+    }
+
+    /// the header plus whatever optional fields the flags say follow it
+    fn header_len(flags: u8) -> u64 {
+        if flags & FLAG_EXPIRES != 0 { HEADER_LEN + EXPIRY_LEN } else { HEADER_LEN }
+    }
+
     /// takes anything that implements the Read trait - could be a file, but could also be a [u8]
-    fn process_record<R: Read>(f: &mut R) -> io::Result<KeyValuePair> {
-        // remember we're passing in a stream of bytes
-        // but it's important which way bytes are formatted - Little or Big endian
-        // here we ensure they're read as LittleEndian, plucking the first 3x 4 bytes = 12 bytes (header in Bitcask)
-        let saved_checksum = f.read_u32::<LittleEndian>()?;
-        let key_len = f.read_u32::<LittleEndian>()?;
-        let val_len = f.read_u32::<LittleEndian>()?;
-        let data_len = key_len + val_len;
+    fn process_record<R: Read>(f: &mut R) -> io::Result<Record> {
+        let header = RecordHeader::read(f)?;
+        let RecordHeader { flags, key_len, expires_at, .. } = header;
+        let data_len = header.data_len();
 
-        // allocated enough space to store our data
-        let mut data = ByteString::with_capacity(data_len as usize);
+        // allocated enough space to store our data (within reason)
+        let mut data = ByteString::with_capacity(data_len.min(MAX_PREALLOC as u64) as usize);
 
         // new scope so that we can drop() the reference to f after we're done
         // the point of this is that after we've dropped, the original f is still intact and can be read again
@@ -92,63 +764,413 @@ impl ActionKV {
             // take a &mut ref to f
             f.by_ref()
                 // decide to read data_len worth of data
-                .take(data_len as u64)
+                .take(data_len)
                 // do it - read into data, which we defined earlier
                 .read_to_end(&mut data)?;
         }
 
-        debug_assert_eq!(data.len(), data_len as usize);
+        // the file ran out before the record did
+        if data.len() as u64 != data_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
 
         //we're using a particular kind of checksum here, crc32. More complex than parit bit, but less complex than crypto hash fns
         //this part is what gives Bitcask it's resiliency and no corruption guarantees
-        let checksum = crc32::checksum_ieee(&data);
-        if checksum != saved_checksum {
-            panic!("checksums don't match");
+        //callers attach the offset via Corruption::locate(), InvalidData is only ever used for this
+        let checksum = ActionKV::checksum(flags, expires_at, &data);
+        if checksum != header.checksum {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "checksums don't match"));
         }
 
         // split vector into K and V
         let value = data.split_off(key_len as usize);
         let key = data;
+        // the checksum covers what's on disk, so only now do we get the real value out
+        let value = if flags & FLAG_COMPRESSED != 0 { compression::decompress(&value)? } else { value };
+
+        Ok(Record { kv: KeyValuePair {key, value}, flags, len: header.record_len(), expires_at })
+    }
 
-        Ok(KeyValuePair {key, value})
+    /// records without flags are checksummed exactly like before flags existed
+    /// once there are flags they're covered too - a flipped bit shouldn't be able to silently turn a value into a tombstone
+    /// (and so is the expiry, so that it can't silently move either)
+    fn checksum(flags: u8, expires_at: Option<u64>, data: &ByteStr) -> u32 {
+        let mut digest = crc32::Digest::new(crc32::IEEE);
+        if flags != 0 {
+            digest.write(&[flags]);
+        }
+        if let Some(expires_at) = expires_at {
+            digest.write(&expires_at.to_le_bytes());
+        }
+        digest.write(data);
+        digest.sum32()
     }
 
-    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-        let position = match self.index.get(key) {
-            None => return Ok(None),
-            Some(position) => *position,
+
+    pub fn get(&self, key: &ByteStr) -> io::Result<Option<ByteString>> {
+        Ok(self.get_value(key)?.map(Value::into_vec))
+    }
+
+    /// like get(), but with Options::mmap the value comes straight out of the segment's map without being copied
+    /// (unless it's in the cache, see Options::cache_size)
+    pub fn get_value(&self, key: &ByteStr) -> io::Result<Option<Value>> {
+        if let Some(value) = self.cache.get(key, now_millis()) {
+            return Ok(Some(value.into()));
+        }
+        // only hold the lock for the lookup - the slow part, reading from disk, happens after it's released
+        let (position, segment) = {
+            let state = self.state();
+            let position = match state.lookup(key)? {
+                None => return Ok(None),
+                Some(position) => position,
+            };
+            (position, Arc::clone(state.segment(position.segment)?))
         };
 
-        let kv = self.get_at(position)?;
+        // an expired key stays in the index until the next compact(), it's only the record that knows it's dead
+        if !self.mmap {
+            let record = ActionKV::read_record(&segment, position)?;
+            if record.is_expired(now_millis()) {
+                return Ok(None);
+            }
+            self.remember(key, position, &record.kv.value, record.expires_at)?;
+            return Ok(Some(record.kv.value.into()));
+        }
+        let record = ActionKV::map_record(&segment, position)?;
+        if is_expired(record.expires_at, now_millis()) {
+            return Ok(None);
+        }
+        // a compressed value can't be handed out as it sits in the map, it has to be unpacked into a buffer of its own
+        if record.compressed {
+            let value = compression::decompress(&record.map[record.value])?;
+            self.remember(key, position, &value, record.expires_at)?;
+            return Ok(Some(value.into()));
+        }
+        self.remember(key, position, &record.map[record.value.clone()], record.expires_at)?;
+        Ok(Some(Value::mapped(record.map, record.value.start, record.value.end)))
+    }
 
-        Ok(Some(kv.value))
+    /// puts a value get() just read into the cache - as long as no writer has replaced it in the meantime
+    /// writers drop the cached value while holding the state lock, so checking under it is enough
+    fn remember(&self, key: &ByteStr, position: Position, value: &ByteStr, expires_at: Option<u64>) -> io::Result<()> {
+        if !self.cache.is_enabled() {
+            return Ok(());
+        }
+        let state = self.state();
+        if state.lookup(key)? == Some(position) {
+            self.cache.insert(key, value, expires_at);
+        }
+        Ok(())
+    }
+
+    /// how often get() found its value in the cache - all zeros without one
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// reads whatever record is at position - unlike get() that includes one that has expired
+    pub fn get_at(&self, position: Position) -> io::Result<KeyValuePair> {
+        let segment = Arc::clone(self.state().segment(position.segment)?);
+        if !self.mmap {
+            return Ok(ActionKV::read_record(&segment, position)?.kv);
+        }
+        let record = ActionKV::map_record(&segment, position)?;
+        let value = &record.map[record.value];
+        let value = if record.compressed { compression::decompress(value)? } else { value.to_vec() };
+        Ok(KeyValuePair { key: record.map[record.key].to_vec(), value })
+    }
+
+    /// finds the record at position in the segment's map and checks it, without copying anything out
+    fn map_record(segment: &Segment, position: Position) -> io::Result<MappedRecord> {
+        let locate = |e| Corruption::locate(e, position.segment, position.offset);
+        let map = segment.map_covering(position.offset + position.len).map_err(locate)?;
+        if position.len < HEADER_LEN {
+            return Err(locate(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let start = position.offset as usize;
+        let end = start + position.len as usize;
+        let damaged = || locate(io::Error::new(io::ErrorKind::InvalidData, "checksums don't match"));
+        // same header as process_record() reads, only straight out of memory
+        // the header disagreeing with the index about the length (running out of record while reading it, even) can only mean it's damaged
+        let header = RecordHeader::read(&mut &map[start..end]).map_err(|_| damaged())?;
+        if header.record_len() != position.len {
+            return Err(damaged());
+        }
+        let data_start = start + header.len() as usize;
+        if ActionKV::checksum(header.flags, header.expires_at, &map[data_start..end]) != header.checksum {
+            return Err(damaged());
+        }
 
+        let value_start = data_start + header.key_len as usize;
+        let expires_at = header.expires_at;
+        let compressed = header.flags & FLAG_COMPRESSED != 0;
+        Ok(MappedRecord { key: data_start..value_start, value: value_start..end, expires_at, compressed, map })
     }
 
-    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
-        // move f into mem
-        let mut f = BufReader::new(&mut self.f);
-        // go to position
-        f.seek(SeekFrom::Start(position))?;
+    fn read_record(segment: &Segment, position: Position) -> io::Result<Record> {
+        // positional reads don't touch the file's cursor, so any number of threads can do this at once
+        let mut f = BufReader::new(segment.reader_at(position.offset));
         // process and return the record
-        let kv = ActionKV::process_record(&mut f)?;
-        Ok(kv)
+        ActionKV::process_record(&mut f).map_err(|e| Corruption::locate(e, position.segment, position.offset))
     }
 
-    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+    /// where key's current value sits on disk, if it has one
+    /// (with a fingerprint index that means reading from disk, which can fail)
+    pub fn position(&self, key: &ByteStr) -> io::Result<Option<Position>> {
+        self.state().lookup(key)
+    }
+
+    /// whether key has a value that hasn't expired - only the record's header is read, not the value
+    pub fn contains_key(&self, key: &ByteStr) -> io::Result<bool> {
+        let (position, segment) = {
+            let state = self.state();
+            match state.lookup(key)? {
+                Some(position) => (position, Arc::clone(state.segment(position.segment)?)),
+                None => return Ok(false),
+            }
+        };
+        // an expired key stays in the index until the next compact(), its header is what knows better
+        let header = RecordHeader::read(&mut segment.reader_at(position.offset))
+            .map_err(|e| Corruption::locate(e, position.segment, position.offset))?;
+        Ok(!is_expired(header.expires_at, now_millis()))
+    }
+
+    /// how many keys there are - counted from the index without going to disk, so that includes keys whose ttl
+    /// has run out but that compact() hasn't got rid of yet
+    pub fn len(&self) -> usize {
+        self.state().index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+        self.insert_expiring(key, value, None)
+    }
+
+    /// like insert(), but once ttl has passed get(), contains_key() and keys() act as if the key isn't there,
+    /// and compact() gets rid of it for good (len() keeps counting it until then)
+    /// the expiry is wall clock time, so it carries on counting while the store is closed
+    pub fn insert_with_ttl(&self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> io::Result<()> {
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        self.insert_expiring(key, value, Some(now_millis().saturating_add(ttl)))
+    }
+
+    fn insert_expiring(&self, key: &ByteStr, value: &ByteStr, expires_at: Option<u64>) -> io::Result<()> {
+        let writer = self.writer.lock().unwrap();
+        let sync_target = self.put(&writer, key, value, expires_at)?;
+        //let the next writer in before waiting on the disk, so that its write can share our sync
+        drop(writer);
+        self.syncer.commit(sync_target)
+    }
+
+    /// insert_expiring() for a caller that already holds the write lock - returns what to hand to syncer.commit()
+    fn put(&self, writer: &WriteGuard, key: &ByteStr, value: &ByteStr, expires_at: Option<u64>) -> io::Result<u64> {
         //insert the actual record
-        let position = self.insert_but_ignore_index(key, value)?;
+        let (positions, sync_target) = self.append(writer, &[(key, value, 0, expires_at)])?;
         //update the index
-        self.index.insert(key.to_vec(), position);
+        let mut state = self.state_mut();
+        state.index_insert(key.to_vec(), positions[0], value)?;
+        self.cache.invalidate(key);
+        Ok(sync_target)
+    }
+
+    /// appends records back to back into the same segment with a single write
+    /// returns where each one went, plus what to hand to syncer.commit() once the write lock has been let go of
+    fn append(&self, writer: &WriteGuard, records: &[NewRecord]) -> io::Result<(Vec<Position>, u64)> {
+        self.check_writable()?;
+        // check up front - failing half way through would leave a partial write behind
+        if records.iter().any(|(key, _, _, _)| key.len() > KEY_LEN_MASK as usize) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "key is longer than 16 MiB"));
+        }
+        let total_len = records
+            .iter()
+            .map(|(key, value, _, expires_at)| {
+                let header_len = if expires_at.is_some() { HEADER_LEN + EXPIRY_LEN } else { HEADER_LEN };
+                header_len + key.len() as u64 + value.len() as u64
+            })
+            .sum();
+        // a background sync that went wrong fails this write, rather than one that's already in the log
+        self.syncer.check()?;
+        let segment = self.active_with_room_for(writer, total_len)?;
+        let id = self.state().active_id();
+
+        // the records are put together in memory first, so they reach the file in one go
+        let mut buf = Vec::with_capacity(total_len as usize);
+        //the file is only appended to while holding the write lock, so its current end is where our records will start
+        let start = segment.len()?;
+        let mut current_position = start;
+        let mut positions = Vec::with_capacity(records.len());
+        for (key, value, flags, expires_at) in records {
+            let len = self.write_value(&mut buf, key, value, *flags, *expires_at)?;
+            positions.push(Position { segment: id, offset: current_position, len });
+            current_position += len;
+        }
+        segment.f.append(&buf)?;
+        self.tail.bump();
+
+        // counted while we still hold the write lock, so that sealing the segment can't miss these bytes when it syncs
+        // nothing from here on can fail, the caller goes straight on to update the index
+        let sync_target = self.syncer.written(current_position - start);
+
+        Ok((positions, sync_target))
+    }
+
+    /// the segment the next record_len bytes should go to
+    /// seals the active segment and starts a new one if they'd push it past max_segment_size
+    /// a record bigger than max_segment_size still has to go somewhere, so it gets a segment to itself
+    fn active_with_room_for(&self, writer: &WriteGuard, record_len: u64) -> io::Result<Arc<Segment>> {
+        let active = Arc::clone(self.state().active());
+        let max = match self.max_segment_size {
+            None => return Ok(active),
+            Some(max) => max,
+        };
+
+        let active_len = active.len()?;
+        // a segment with nothing but its file header in it is as empty as it gets
+        if active_len <= FILE_HEADER_LEN || active_len + record_len <= max {
+            return Ok(active);
+        }
+        self.seal_active(writer)
+    }
+
+    /// flushes the active segment to disk and starts a new one after it, returning the new one
+    /// the old one is never written to again
+    fn seal_active(&self, _writer: &WriteGuard) -> io::Result<Arc<Segment>> {
+        let active = Arc::clone(self.state().active());
+        active.f.sync()?;
+
+        let id = self.state().active_id();
+        let new_id = id + 1;
+        let dir = self.path.as_ref().expect("only a store kept in files has segments");
+        let path = dir.join(segment::file_name(new_id, new_id));
+        let f = ActionKV::open_data_file(&path, true)?;
+        self.syncer.switch_file(Arc::clone(&f))?;
+        let segment = Arc::new(Segment::new(new_id, Some(path), f));
+        self.state_mut().segments.insert(new_id, Arc::clone(&segment));
+        Ok(segment)
+    }
+
+    /// walks every record on disk and works out how much of it is still live
+    /// expired and damaged records count as dead, since compact() or recover() would get rid of them
+    pub fn stats(&self) -> io::Result<Stats> {
+        let segments: Vec<(u32, Arc<Segment>)> = self.state()
+            .segments
+            .iter()
+            .map(|(id, segment)| (*id, Arc::clone(segment)))
+            .collect();
+        let mut stats = Stats { segments: segments.len(), ..Stats::default() };
+        let now = now_millis();
+
+        for (id, segment) in segments {
+            ActionKV::for_each_record(&segment, |offset, len, record| {
+                stats.records += 1;
+                let live = match record {
+                    Ok(record) if !record.is_expired(now) => self.state()
+                        .index
+                        .points_at(&record.kv.key, Position { segment: id, offset, len }),
+                    _ => false,
+                };
+                if live {
+                    stats.live_records += 1;
+                    stats.live_bytes += len;
+                } else {
+                    stats.dead_bytes += len;
+                }
+            })?;
+        }
+        Ok(stats)
+    }
+
+    /// checks the checksum of every record on disk, without touching anything
+    /// unlike load() it carries on past damaged records, so you get the full list in one go - an empty one means all is well
+    /// works on a store that hasn't been loaded (and can't be, because of the damage)
+    pub fn verify(&self) -> io::Result<Vec<Corruption>> {
+        let segments: Vec<(u32, Arc<Segment>)> = self.state()
+            .segments
+            .iter()
+            .map(|(id, segment)| (*id, Arc::clone(segment)))
+            .collect();
+        let mut damaged = vec![];
+
+        for (id, segment) in segments {
+            ActionKV::for_each_record(&segment, |offset, _, record| {
+                if let Err(e) = record {
+                    let corruption = match e.kind() {
+                        io::ErrorKind::InvalidData => Corruption::ChecksumMismatch { segment: id, offset },
+                        _ => Corruption::TornRecord { segment: id, offset },
+                    };
+                    damaged.push(corruption);
+                }
+            })?;
+        }
+        Ok(damaged)
+    }
+
+    /// calls visit(offset, len, record) for every record in the segment, front to back
+    /// a record with a bad checksum is handed over as an error and skipped, a torn one ends the segment (len is whatever's left of the file)
+    /// any other error stops the walk and is returned
+    fn for_each_record<F>(segment: &Segment, mut visit: F) -> io::Result<()>
+    where
+        F: FnMut(u64, u64, io::Result<Record>),
+    {
+        let file_len = segment.len()?;
+        let mut f = BufReader::new(segment.reader_at(FILE_HEADER_LEN));
+        let mut offset = FILE_HEADER_LEN;
+
+        while offset < file_len {
+            match ActionKV::process_record(&mut f) {
+                Ok(record) => {
+                    let len = record.len;
+                    visit(offset, len, Ok(record));
+                    offset += len;
+                },
+                // process_record() got through the whole record before checking it, so f is already at the next one
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    let len = ActionKV::record_len_at(segment, offset)?;
+                    visit(offset, len, Err(e));
+                    offset += len;
+                },
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    visit(offset, file_len - offset, Err(e));
+                    break;
+                },
+                Err(e) => return Err(e),
+            }
+        }
         Ok(())
     }
 
-    fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-        // move f into mem
-        let mut f = BufWriter::new(&mut self.f);
+    /// the files making up the store, oldest first - none for a store that isn't kept in files
+    /// in a segmented store all but the last are immutable, so they can be copied somewhere safe while the store is in use
+    pub fn segment_paths(&self) -> Vec<PathBuf> {
+        self.state().segments.values().filter_map(|segment| segment.path.clone()).collect()
+    }
+
+    /// write_record(), compressing the value first if the store is set up for that
+    /// only values worth keeping get compressed - tombstones and batch markers are left alone
+    fn write_value<W: Write>(&self, f: &mut W, key: &ByteStr, value: &ByteStr, flags: u8, expires_at: Option<u64>) -> io::Result<u64> {
+        if flags & (FLAG_TOMBSTONE | FLAG_BATCH_BEGIN | FLAG_BATCH_COMMIT) == 0 {
+            if let Some(compressed) = self.compression.compress(value) {
+                return ActionKV::write_record(f, key, &compressed, flags | FLAG_COMPRESSED, expires_at);
+            }
+        }
+        ActionKV::write_record(f, key, value, flags, expires_at)
+    }
 
+    /// writes a single record (header + body) into anything that implements Write
+    /// returns the number of bytes written, so callers writing several records in a row can keep track of offsets
+    /// expires_at sets FLAG_EXPIRES and puts the expiry right after the header
+    fn write_record<W: Write>(f: &mut W, key: &ByteStr, value: &ByteStr, flags: u8, expires_at: Option<u64>) -> io::Result<u64> {
+        let flags = if expires_at.is_some() { flags | FLAG_EXPIRES } else { flags };
         // create a tmp buffer with enough space
         let key_len = key.len();
+        // the top byte of key_len is taken by the flags, so bigger keys simply can't be represented
+        if key_len > KEY_LEN_MASK as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "key is longer than 16 MiB"));
+        }
         let val_len = value.len();
         let data_len = key_len + val_len;
         let mut tmp = ByteString::with_capacity(data_len);
@@ -162,32 +1184,703 @@ impl ActionKV {
         }
 
         // prep the checksum
-        let checksum = crc32::checksum_ieee(&tmp);
-
-        //move to the end of the file
-        let next_byte = SeekFrom::End(0);
-        //now seek from the current position (which is the end of the file, since we just moved there)
-        let current_position = f.seek(SeekFrom::Current(0))?;
-        //move to next byte
-        f.seek(next_byte)?;
+        let checksum = ActionKV::checksum(flags, expires_at, &tmp);
 
         //write header (12 bytes: checksum, key len, val len)
         f.write_u32::<LittleEndian>(checksum)?;
-        f.write_u32::<LittleEndian>(key_len as u32)?;
+        f.write_u32::<LittleEndian>((key_len as u32) | ((flags as u32) << FLAGS_SHIFT))?;
         f.write_u32::<LittleEndian>(val_len as u32)?;
+        if let Some(expires_at) = expires_at {
+            f.write_u64::<LittleEndian>(expires_at)?;
+        }
         //write body
-        f.write_all(&mut tmp)?;
+        f.write_all(&tmp)?;
+
+        Ok(ActionKV::header_len(flags) + data_len as u64)
+    }
+
+
+    /// applies every write in the batch, or - if we crash part way through - none of them
+    pub fn write(&self, batch: &WriteBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let count = (batch.len() as u32).to_le_bytes();
+
+        // begin marker, the writes themselves, commit marker - all in one go
+        let mut records: Vec<NewRecord> = Vec::with_capacity(batch.len() + 2);
+        records.push((b"", &count, FLAG_BATCH_BEGIN, None));
+        for (key, value) in &batch.ops {
+            match value {
+                Some(value) => records.push((key, value, FLAG_BATCHED, None)),
+                None => records.push((key, b"", FLAG_BATCHED | FLAG_TOMBSTONE, None)),
+            }
+        }
+        records.push((b"", &count, FLAG_BATCH_COMMIT, None));
+
+        let writer = self.writer.lock().unwrap();
+        let (positions, sync_target) = self.append(&writer, &records)?;
+
+        // the commit marker is in the file, only now does the batch become visible - and all of it at once
+        {
+            let mut state = self.state_mut();
+            for ((key, value), position) in batch.ops.iter().zip(&positions[1..]) {
+                match value {
+                    Some(value) => state.index_insert(key.clone(), *position, value)?,
+                    None => state.index_remove(key)?,
+                }
+                self.cache.invalidate(key);
+            }
+        }
+        drop(writer);
+        self.syncer.commit(sync_target)
+    }
+
+    /// every live key - in key order, served from the index without reading the log, only each key's record header
+    /// is read to leave out keys whose ttl has run out. with a fingerprint index the keys aren't in memory, so they're
+    /// streamed from the data files in the order they were written instead, same as iter() - and like iter(),
+    /// a compact() part way through ends that with a Compacted error
+    pub fn keys(&self) -> impl Iterator<Item = io::Result<ByteString>> + '_ {
+        let keys: Box<dyn Iterator<Item = io::Result<ByteString>> + '_> = if self.state().index.holds_keys() {
+            Box::new(Range::new(self, ..).keys())
+        } else {
+            Box::new(self.iter().map(|kv| kv.map(|kv| kv.key)))
+        };
+        keys
+    }
+
+    /// every live key/value pair, streamed from the data files in the order they were written
+    /// only one record is held in memory at a time, however big the store is
+    /// a compact() that runs while it's part way through ends it with a Compacted error (see Compacted::is())
+    pub fn iter(&self) -> Iter<'_> {
+        let state = self.state();
+        let segments = state.segments
+            .iter()
+            .map(|(id, segment)| (*id, Arc::clone(segment)))
+            .collect();
+        Iter::new(self, segments, state.generation)
+    }
+
+    /// every live value, same order as iter()
+    pub fn values(&self) -> impl Iterator<Item = io::Result<ByteString>> + '_ {
+        self.iter().map(|kv| kv.map(|kv| kv.value))
+    }
+
+    /// every key/value pair with start <= key < end (or whatever bounds the range has), in key order
+    /// eg `store.range(b"a".to_vec()..b"c".to_vec())`
+    /// values are only read from disk as the iterator gets to them
+    pub fn range<R: RangeBounds<ByteString>>(&self, range: R) -> Range<'_> {
+        Range::new(self, range)
+    }
 
-        Ok(current_position)
+    /// every key/value pair whose key starts with prefix, in key order
+    pub fn scan_prefix(&self, prefix: &ByteStr) -> Range<'_> {
+        Range::new(self, scan::prefix_range(prefix))
     }
 
-    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+    pub fn update(&self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
         //we simply insert the new value since this is an append only data store
         self.insert(key, value)
     }
 
-    pub fn delete(&mut self, key: &ByteStr) -> io::Result<()> {
-        //we simply insert an EMPTY value since this is an append only data store
-        self.insert(key, b"")
+    pub fn delete(&self, key: &ByteStr) -> io::Result<()> {
+        let writer = self.writer.lock().unwrap();
+        let sync_target = match self.tombstone(&writer, key)? {
+            Some(sync_target) => sync_target,
+            None => return Ok(()),
+        };
+        drop(writer);
+        self.syncer.commit(sync_target)
+    }
+
+    /// delete() for a caller that already holds the write lock - returns what to hand to syncer.commit(), if anything was written
+    fn tombstone(&self, writer: &WriteGuard, key: &ByteStr) -> io::Result<Option<u64>> {
+        //nothing to delete, and no point growing the file with a tombstone for it
+        if self.state().lookup(key)?.is_none() {
+            return Ok(None);
+        }
+        //we can't remove anything from an append only data store, so instead we append a tombstone
+        //load() will see it after the key's earlier versions and drop the key from the index
+        let (_, sync_target) = self.append(writer, &[(key, b"", FLAG_TOMBSTONE, None)])?;
+        let mut state = self.state_mut();
+        state.index_remove(key)?;
+        self.cache.invalidate(key);
+        Ok(Some(sync_target))
+    }
+
+    /// rewrites the data file so that it only contains the records currently pointed to by the index
+    /// every update and delete leaves a dead version of the key behind, so without this the file grows forever
+    /// tombstones aren't in the index, so they get dropped too - nothing older is left for them to shadow
+    /// keys whose ttl has run out are dropped here as well
+    /// a fresh hint file is written afterwards, so the next load() doesn't have to read the data at all
+    ///
+    /// a segmented store seals its active segment first and then merges every segment into one,
+    /// writes carry on in a fresh segment after it - see compact_segments() for merging only some of them
+    /// writers wait until compaction is done, readers carry on as normal
+    pub fn compact(&self) -> io::Result<()> {
+        self.check_writable()?;
+        let writer = self.writer.lock().unwrap();
+
+        if self.max_segment_size.is_some() && self.state().active().len()? > FILE_HEADER_LEN {
+            self.seal_active(&writer)?;
+        }
+        // a segmented store leaves its (now empty) active segment alone, a single file store rewrites its only file
+        let merged: Vec<(u32, Arc<Segment>)> = {
+            let state = self.state();
+            let take = match self.max_segment_size {
+                None => state.segments.len(),
+                Some(_) => state.segments.len() - 1,
+            };
+            state.segments.iter().take(take).map(|(id, segment)| (*id, Arc::clone(segment))).collect()
+        };
+        self.compact_run(&writer, merged)
+    }
+
+    /// compact() for just the sealed segments with an id in ids, merged into one - the rest are left alone
+    /// eg for keeping the work (and the extra disk space) of each compaction down, by merging a few old segments at a time
+    /// only a segmented store has segments to pick from, for the others it's InvalidInput
+    ///
+    /// unlike compact(), with older segments left in front of the merged one this has to keep a tombstone for every key
+    /// the run deleted (and turn keys that have expired into one), otherwise load() would bring back
+    /// whatever value those older segments still have for them - they go once compact() gets to merge everything
+    pub fn compact_segments(&self, ids: RangeInclusive<u32>) -> io::Result<()> {
+        self.check_writable()?;
+        if self.max_segment_size.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "only a segmented store can compact some of its segments"));
+        }
+        let writer = self.writer.lock().unwrap();
+        // the active segment is still being written to, so it's never part of the run
+        let merged: Vec<(u32, Arc<Segment>)> = {
+            let state = self.state();
+            let active_id = state.active_id();
+            state.segments
+                .range(ids)
+                .filter(|(id, _)| **id != active_id)
+                .map(|(id, segment)| (*id, Arc::clone(segment)))
+                .collect()
+        };
+        self.compact_run(&writer, merged)
     }
-}
\ No newline at end of file
+
+    /// merges a run of segments (in order, with nothing in between) into one file that replaces all of them
+    fn compact_run(&self, _writer: &WriteGuard, merged: Vec<(u32, Arc<Segment>)>) -> io::Result<()> {
+        let (first_id, first, last) = match (merged.first(), merged.last()) {
+            (Some((first_id, first)), Some((last, _))) => (*first_id, first.first, *last),
+            _ => return Ok(()),
+        };
+        // with nothing older left, whatever the run says about deleted keys has nothing to shadow any more
+        let older_left = self.state().segments.range(..first_id).next().is_some();
+        let deleted = if older_left { Some(self.deleted_in(&merged)?) } else { None };
+
+        // on disk before the new file is, so there's no way of restarting with the new file but the old generation
+        let generation = generation::next(self.state().generation);
+        if let Some(generation_path) = self.aux_path("generation") {
+            generation::write(&generation_path, generation)?;
+        }
+
+        // sort by position so that we read the old files front to back instead of jumping all over them
+        let mut live: Vec<Position> = self.state()
+            .index
+            .positions()
+            .filter(|position| (first_id..=last).contains(&position.segment))
+            .copied()
+            .collect();
+        live.sort_by_key(|position| (position.segment, position.offset));
+
+        let (merged_path, f, copied) = match &self.path {
+            Some(path) => {
+                let merged_path = match self.max_segment_size {
+                    None => path.clone(),
+                    Some(_) => path.join(segment::file_name(first, last)),
+                };
+                let (f, copied) = self.compact_into_file(&merged_path, live, last, deleted)?;
+                (Some(merged_path), f, copied)
+            },
+            // nothing to rename - the new copy is put together in memory and swapped in below
+            None => {
+                let mut buf = format::header().to_vec();
+                let copied = self.copy_live(&mut buf, live, last, deleted)?;
+                let f = self.state().active().f.empty()?;
+                f.append(&buf)?;
+                (None, f, copied)
+            },
+        };
+
+        if self.max_segment_size.is_none() {
+            // the merged file is the one we append to from now on
+            self.syncer.switch_file(Arc::clone(&f))?;
+        }
+        {
+            let mut state = self.state_mut();
+            for (id, _) in &merged {
+                state.segments.remove(id);
+            }
+            state.segments.insert(last, Arc::new(Segment::new(first, merged_path.clone(), f)));
+            state.generation = generation;
+            // nobody else has written since we looked, so every live position is still where we found it
+            let expired: Vec<Position> = copied.expired.iter().map(|(position, _)| *position).collect();
+            state.index.relocate(&copied.moved, &expired);
+            for (_, key) in &copied.expired {
+                state.secondary.remove(key);
+            }
+        }
+        // subscriptions waiting on the old files need to find out they're gone
+        self.tail.bump();
+
+        // the merged segment stands in for all the ones it was made from
+        // if we crash before they're all gone, segment::list() finishes the job on the next open
+        for (_, old) in merged {
+            if let Some(old_path) = old.path.as_ref().filter(|old_path| Some(*old_path) != merged_path.as_ref()) {
+                fs::remove_file(old_path)?;
+            }
+        }
+
+        self.write_hint()
+    }
+
+    /// compact() for a store kept in files: writes the live records into a temp file and renames it over merged_path
+    /// returns the new file, opened for appending, plus what went into it
+    fn compact_into_file(&self, merged_path: &Path, live: Vec<Position>, last: u32, deleted: Option<Vec<ByteString>>) -> io::Result<(Arc<dyn Storage>, Copied)> {
+        // the live records go into a temp file sitting right next to the real one
+        // same directory = same filesystem, which is what makes the rename() below atomic
+        let tmp_path = ActionKV::sibling_path(merged_path, "compact");
+        let tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut w = BufWriter::new(tmp);
+        w.write_all(&format::header())?;
+        let copied = self.copy_live(&mut w, live, last, deleted)?;
+
+        // everything has to actually be on disk before we swap it in, otherwise a crash could leave us with a half written file
+        let tmp = w.into_inner().map_err(|e| e.into_error())?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        // the old hint describes the old files - get rid of it first, so a crash right after the rename can't pair it with the new ones
+        if let Some(hint_path) = self.aux_path("hint") {
+            ActionKV::remove_if_exists(&hint_path)?;
+        }
+
+        // the atomic part - new readers either see the old file(s) or the new one, never a mix
+        // readers already part way through a get() hold on to the old file, which stays readable until they let go of it
+        fs::rename(&tmp_path, merged_path)?;
+        ActionKV::sync_parent_dir(merged_path)?;
+
+        // our old handle still points at the old (now unlinked) file, so reopen
+        let f = ActionKV::open_data_file(merged_path, true)?;
+        Ok((f, copied))
+    }
+
+    /// writes the records at the given positions into w, one after the other straight after the file header
+    /// the new positions are all in segment last
+    /// deleted is Some when there are older segments left (see compact_segments()) - those keys get a tombstone,
+    /// and so does every key that has expired, so the older segments can't bring them back
+    fn copy_live<W: Write>(&self, w: &mut W, live: Vec<Position>, last: u32, deleted: Option<Vec<ByteString>>) -> io::Result<Copied> {
+        let mut moved = Vec::with_capacity(live.len());
+        let mut expired = vec![];
+        let now = now_millis();
+        let mut offset = FILE_HEADER_LEN;
+        let shadowing = deleted.is_some();
+        for key in deleted.into_iter().flatten() {
+            offset += self.write_value(w, &key, b"", FLAG_TOMBSTONE, None)?;
+        }
+        for old_position in live {
+            let segment = Arc::clone(self.state().segment(old_position.segment)?);
+            let record = ActionKV::read_record(&segment, old_position)?;
+            // this is where expired keys finally go away - they're simply not copied over
+            if record.is_expired(now) {
+                if shadowing {
+                    offset += self.write_value(w, &record.kv.key, b"", FLAG_TOMBSTONE, None)?;
+                }
+                expired.push((old_position, record.kv.key));
+                continue;
+            }
+            // values get (re)compressed according to the current setting, so compacting is also how an existing store gets converted
+            let len = self.write_value(w, &record.kv.key, &record.kv.value, 0, record.expires_at)?;
+            moved.push((old_position, Position { segment: last, offset, len }));
+            offset += len;
+        }
+        Ok(Copied { moved, expired })
+    }
+
+    /// the keys whose last word in these segments is a delete, and that haven't been written again since
+    /// read front to back the way load() would, so only batches that got committed count
+    fn deleted_in(&self, run: &[(u32, Arc<Segment>)]) -> io::Result<Vec<ByteString>> {
+        let mut deleted = HashSet::new();
+        for (id, segment) in run {
+            let mut batches = Batches::new();
+            let mut damaged = None;
+            ActionKV::for_each_record(segment, |offset, _, record| {
+                let record = match record {
+                    Ok(record) => record,
+                    // a damaged record could have been a delete - without it we can't tell what the run leaves behind
+                    Err(e) => {
+                        damaged.get_or_insert(Corruption::locate(e, *id, offset));
+                        return;
+                    },
+                };
+                let ops = match batches.next(record, offset, |record| (record.is_tombstone(), record.kv.key)) {
+                    Framed::Single(record) => vec![(record.is_tombstone(), record.kv.key)],
+                    Framed::Committed(ops) => ops,
+                    Framed::Held => vec![],
+                };
+                for (is_tombstone, key) in ops {
+                    if is_tombstone {
+                        deleted.insert(key);
+                    } else {
+                        deleted.remove(&key);
+                    }
+                }
+            })?;
+            if let Some(e) = damaged {
+                return Err(e);
+            }
+        }
+        // a key that's live again is in a later segment, and that value shadows the older ones just as well
+        let state = self.state();
+        let mut still_deleted = vec![];
+        for key in deleted {
+            if state.lookup(&key)?.is_none() {
+                still_deleted.push(key);
+            }
+        }
+        Ok(still_deleted)
+    }
+
+    /// forces everything written so far onto the disk, whatever the durability mode
+    pub fn sync(&self) -> io::Result<()> {
+        self.syncer.sync()
+    }
+
+    /// makes sure everything written so far is on disk and leaves a hint file behind for the next load()
+    /// (a read-only store has nothing to do here)
+    pub fn close(self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.state().active().f.sync()?;
+        self.write_hint()
+    }
+
+    /// the hint covers everything up to the current end of the active segment
+    /// (a store that isn't kept in files doesn't get one, it's read from scratch every time anyway - and neither does
+    /// one with a fingerprint index, see load())
+    fn write_hint(&self) -> io::Result<()> {
+        let state = self.state();
+        let hint_path = match self.aux_path("hint") {
+            Some(hint_path) if state.index.holds_keys() => hint_path,
+            _ => return Ok(()),
+        };
+        let covered_len = state.active().len()?;
+        let segments = &state.segments;
+        let read_key = |position| ActionKV::key_at(segments, position);
+        hint::write(&hint_path, state.active_id(), covered_len, state.index.entries(&read_key))
+    }
+
+    fn remove_if_exists(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// eg (dbs/store, "compact") -> dbs/store.compact
+    pub(crate) fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(extension);
+        path.with_file_name(name)
+    }
+
+    /// writes contents to a temp file and renames that into place, so there's never a half written file under the real name
+    pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+        let tmp_path = ActionKV::sibling_path(path, "tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(contents)?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, path)?;
+        ActionKV::sync_parent_dir(path)
+    }
+
+    /// a rename is only durable once the directory holding the file has been fsync'd as well
+    #[cfg(not(target_os = "windows"))]
+    pub(crate) fn sync_parent_dir(path: &Path) -> io::Result<()> {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            // a bare file name like "store" lives in the current dir
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()
+    }
+
+    // windows doesn't let us open a directory as a File, and its rename is durable enough for our purposes
+    #[cfg(target_os = "windows")]
+    pub(crate) fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a data file as it would be on disk: the file header, then every record one after the other
+    pub(crate) fn data_file(records: &[(&ByteStr, &ByteStr, u8)]) -> Vec<u8> {
+        let mut data = format::header().to_vec();
+        for (key, value, flags) in records {
+            ActionKV::write_record(&mut data, key, value, *flags, None).unwrap();
+        }
+        data
+    }
+
+    fn recovered(data: Vec<u8>) -> (ActionKV, RecoveryReport) {
+        let mut store = ActionKV::with_storage(MemoryStorage::from(data), Options::default()).unwrap();
+        let report = store.recover().unwrap();
+        (store, report)
+    }
+
+    #[test]
+    fn recover_truncates_a_torn_tail() {
+        let mut data = data_file(&[(b"a", b"1", 0), (b"b", b"2", 0)]);
+        let whole = data.len();
+        data.extend_from_slice(&data_file(&[(b"c", b"3", 0)])[FILE_HEADER_LEN as usize..][..5]);
+
+        // a plain load() doesn't guess
+        let mut store = ActionKV::with_storage(MemoryStorage::from(data.clone()), Options::default()).unwrap();
+        assert!(store.load().is_err());
+
+        let (store, report) = recovered(data);
+        assert_eq!(report.truncated_bytes, 5);
+        assert!(report.skipped.is_empty());
+        assert_eq!(store.state().active().len().unwrap(), whole as u64);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+    }
+
+    #[test]
+    fn recover_skips_a_record_with_a_bad_checksum() {
+        let mut data = data_file(&[(b"a", b"1", 0), (b"b", b"2", 0), (b"c", b"3", 0)]);
+        let b_offset = data_file(&[(b"a", b"1", 0)]).len();
+        // the last byte of b's value
+        let b_end = data_file(&[(b"a", b"1", 0), (b"b", b"2", 0)]).len();
+        data[b_end - 1] ^= 0xff;
+
+        let (store, report) = recovered(data);
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].offset, b_offset as u64);
+        assert_eq!(report.skipped[0].len, (b_end - b_offset) as u64);
+        // nowhere to put it for a store that isn't kept in files
+        assert_eq!(report.quarantine, None);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn recover_quarantines_skipped_records() {
+        let dir = std::env::temp_dir().join(format!("akv-quarantine-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("store");
+
+        let mut data = data_file(&[(b"a", b"1", 0), (b"b", b"2", 0)]);
+        let b_offset = data_file(&[(b"a", b"1", 0)]).len();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        let damaged = data[b_offset..].to_vec();
+        fs::write(&path, &data).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        let report = store.recover().unwrap();
+        let quarantine = report.quarantine.clone().expect("a damaged record was skipped");
+        assert_eq!(fs::read(&quarantine).unwrap(), damaged);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        drop(store);
+
+        // recover() compacted the damaged record away, so a plain load() is happy again
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.len(), 1);
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn loaded(path: &Path, options: Options) -> ActionKV {
+        let mut store = ActionKV::open_with(path, options).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    #[test]
+    fn load_from_a_hint_gives_the_same_index_as_reading_everything() {
+        let dir = std::env::temp_dir().join(format!("akv-hint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let segmented = Options { max_segment_size: Some(64), ..Options::default() };
+        for (path, options) in [(dir.join("single"), Options::default()), (dir.join("segmented"), segmented)].iter() {
+            let store = loaded(path, options.clone());
+            for key in [b"a", b"b", b"c", b"d"].iter() {
+                store.insert(*key, b"1").unwrap();
+            }
+            store.insert(b"b", b"2").unwrap();
+            store.delete(b"c").unwrap();
+            store.close().unwrap();
+            let hint_path = ActionKV::aux_path_of(path, options.max_segment_size.is_some(), "hint");
+            assert!(hint_path.exists());
+
+            // written after the hint, so load() still has to read these from the data files
+            let store = loaded(path, options.clone());
+            store.insert(b"e", b"1").unwrap();
+            store.insert(b"b", b"3").unwrap();
+            store.delete(b"a").unwrap();
+            drop(store);
+
+            // read-only, so both can have it open at once
+            let reader = Options { read_only: true, ..options.clone() };
+            let from_hint = loaded(path, reader.clone());
+            let hint = hint::read(&hint_path).unwrap().expect("close() left a hint");
+            assert!(ActionKV::hint_matches(&from_hint.state(), &hint).unwrap());
+            fs::remove_file(&hint_path).unwrap();
+            let from_scratch = loaded(path, reader);
+            assert_eq!(from_hint.state().index, from_scratch.state().index);
+            assert_eq!(from_hint.get(b"b").unwrap(), Some(b"3".to_vec()));
+            assert_eq!(from_hint.get(b"a").unwrap(), None);
+            assert_eq!(from_hint.len(), 3);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hint_matches_turns_down_hints_from_some_other_store() {
+        let data = data_file(&[(b"a", b"1", 0)]);
+        let len = data.len() as u64;
+        let store = ActionKV::with_storage(MemoryStorage::from(data), Options::default()).unwrap();
+        let state = store.state();
+        let hint = |covered_segment, covered_len, segment| hint::Hint {
+            covered_segment,
+            covered_len,
+            entries: vec![(b"a".to_vec(), Position { segment, offset: FILE_HEADER_LEN, len: len - FILE_HEADER_LEN })],
+        };
+
+        assert!(ActionKV::hint_matches(&state, &hint(0, len, 0)).unwrap());
+        // the data file is shorter than the hint remembers, so it's been rewritten since
+        assert!(!ActionKV::hint_matches(&state, &hint(0, len + 1, 0)).unwrap());
+        // segments that aren't there (any more)
+        assert!(!ActionKV::hint_matches(&state, &hint(1, len, 0)).unwrap());
+        assert!(!ActionKV::hint_matches(&state, &hint(0, len, 3)).unwrap());
+    }
+
+    #[test]
+    fn compact_segments_keeps_tombstones_for_what_older_segments_still_have() {
+        let dir = std::env::temp_dir().join(format!("akv-compact-segments-{}", std::process::id()));
+        let options = Options { max_segment_size: Some(64), ..Options::default() };
+        // big enough that every record gets a segment of its own
+        let value = [b'x'; 40];
+
+        let store = loaded(&dir, options.clone());
+        store.insert(b"a", &value).unwrap();
+        store.insert(b"b", &value).unwrap();
+        let older = store.state().active_id();
+        store.delete(b"a").unwrap();
+        store.insert_with_ttl(b"b", &value, Duration::from_millis(1)).unwrap();
+        store.insert(b"c", &value).unwrap();
+        store.insert(b"d", &value).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        store.compact_segments(older + 1..=u32::MAX).unwrap();
+        {
+            let state = store.state();
+            // the two older segments, the merged one and the active one
+            assert_eq!(state.segments.len(), 4);
+            assert_eq!(state.segments.range(..=older).count(), 2);
+        }
+        drop(store);
+
+        // no hint, so it's down to what's in the data files - a's tombstone and the one standing in for b's expiry
+        // are what stop the values in the older segments coming back
+        fs::remove_file(ActionKV::aux_path_of(&dir, true, "hint")).ok();
+        let store = loaded(&dir, options);
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(value.to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(value.to_vec()));
+        drop(store);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn expired_keys_stay_gone_through_load_and_compact() {
+        let past = now_millis() - 1000;
+        let future = now_millis() + 3_600_000;
+        let mut data = data_file(&[(b"a", b"1", 0)]);
+        // an overwrite that has expired takes the value it replaced with it
+        ActionKV::write_record(&mut data, b"a", b"2", 0, Some(past)).unwrap();
+        ActionKV::write_record(&mut data, b"b", b"3", 0, Some(future)).unwrap();
+        ActionKV::write_record(&mut data, b"c", b"4", 0, Some(past)).unwrap();
+
+        let mut store = ActionKV::with_storage(MemoryStorage::from(data), Options::default()).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), None);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.len(), 1);
+
+        // one that runs out while the store is open stays in the index, but nothing hands it out
+        store.insert_expiring(b"d", b"5", Some(now_millis() + 20)).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.get(b"d").unwrap(), None);
+        assert!(!store.contains_key(b"d").unwrap());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.keys().collect::<io::Result<Vec<_>>>().unwrap(), vec![b"b".to_vec()]);
+
+        // compact() drops it, and b keeps its expiry
+        store.compact().unwrap();
+        assert_eq!(store.len(), 1);
+        let state = store.state();
+        let mut compacted = vec![0; state.active().len().unwrap() as usize];
+        state.active().read_exact_at(&mut compacted, 0).unwrap();
+        let mut expected = format::header().to_vec();
+        ActionKV::write_record(&mut expected, b"b", b"3", 0, Some(future)).unwrap();
+        assert_eq!(compacted, expected);
+    }
+
+    #[test]
+    fn migrate_adds_the_header_to_every_legacy_file_once() {
+        let dir = std::env::temp_dir().join(format!("akv-migrate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // records the way they were written before data files had a header
+        let legacy = |records: &[(&ByteStr, &ByteStr, u8)]| data_file(records)[FILE_HEADER_LEN as usize..].to_vec();
+        let segmented_path = dir.join("segmented");
+        fs::create_dir_all(&segmented_path).unwrap();
+        fs::write(segmented_path.join(segment::file_name(1, 1)), legacy(&[(b"a", b"1", 0)])).unwrap();
+        fs::write(segmented_path.join(segment::file_name(2, 2)), legacy(&[(b"b", b"2", 0), (b"a", b"", FLAG_TOMBSTONE)])).unwrap();
+        let single_path = dir.join("single");
+        fs::write(&single_path, legacy(&[(b"b", b"2", 0)])).unwrap();
+
+        for (path, options, files) in [
+            (&segmented_path, Options { max_segment_size: Some(1024), ..Options::default() }, 2),
+            (&single_path, Options::default(), 1),
+        ].iter() {
+            let segmented = options.max_segment_size.is_some();
+            // a hint from before, its offsets don't allow for the header
+            let hint_path = ActionKV::aux_path_of(path, segmented, "hint");
+            fs::write(&hint_path, b"stale").unwrap();
+
+            let refused = ActionKV::open_with(path, options.clone()).and_then(|mut store| store.load()).unwrap_err();
+            assert!(matches!(refused.get_ref().and_then(|e| e.downcast_ref::<FormatError>()), Some(FormatError::Legacy { .. })));
+
+            assert_eq!(ActionKV::migrate(path).unwrap(), *files);
+            assert!(!hint_path.exists());
+            // nothing left to do the second time round
+            assert_eq!(ActionKV::migrate(path).unwrap(), 0);
+
+            let store = loaded(path, options.clone());
+            assert_eq!(store.get(b"a").unwrap(), None);
+            assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+//! advisory locking, so that two processes can't both append to a store and interleave their records
+//! the lock lives on a file of its own (store.lock) rather than on the data - compact() renames a new data file
+//! over the old one, which would quietly take the lock with it
+//!
+//! writers take it exclusively, read-only opens share it - so any number of readers, or a single writer
+//! it's advisory: only other ActionKVs (and anything else that asks for it) respect it
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// locks the lock file at path, creating it if need be
+/// the lock is held for as long as the returned file is open
+pub(crate) fn acquire(path: &Path, store: &Path, exclusive: bool) -> io::Result<File> {
+    let f = match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path) {
+        Ok(f) => f,
+        // a reader may not be allowed to write next to the store - it can still lock a lock file that's already there
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && !exclusive => File::open(path)?,
+        Err(e) => return Err(e),
+    };
+    let locked = if exclusive { f.try_lock() } else { f.try_lock_shared() };
+    match locked {
+        Ok(()) => Ok(f),
+        Err(std::fs::TryLockError::WouldBlock) => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is already in use by another process", store.display()),
+        )),
+        Err(std::fs::TryLockError::Error(e)) => Err(e),
+    }
+}
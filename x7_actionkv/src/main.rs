@@ -1,72 +1,412 @@
-use libactionkv::ActionKV;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use libactionkv::{ActionKV, Options};
+use serde::{Deserialize, Serialize};
 
 // conditional compilation - below only compiles on windows, while the next block only on non-windows
 #[cfg(target_os = "windows")]
 const USAGE: &str = "
 Usage:
-    cargo.exe run -- FILE get KEY
-    cargo.exe run -- FILE delete KEY
-    cargo.exe run -- FILE insert KEY VALUE
-    cargo.exe run -- FILE update KEY VALUE
+    cargo.exe run -- [--hex | --base64] FILE COMMAND [ARGS]
 ";
 
 #[cfg(not(target_os = "windows"))]
 const USAGE: &str = "
 Usage:
-    cargo run -- FILE get KEY
-    cargo run -- FILE delete KEY
-    cargo run -- FILE insert KEY VALUE
-    cargo run -- FILE update KEY VALUE
+    cargo run -- [--hex | --base64] FILE COMMAND [ARGS]
+";
+
+const COMMANDS: &str = "
+Commands:
+    get KEY              print the value of KEY
+    delete KEY
+    insert KEY VALUE
+    update KEY VALUE
+    list                 print every key, in order
+    scan PREFIX          print every key starting with PREFIX and its value, tab separated
+    stats                record count, live vs dead bytes
+    compact              drop everything that isn't live any more
+    verify               check every record's checksum, without loading the store
+    recover              skip damaged records and cut off torn ones, so the store loads again
+    migrate              upgrade a store written before data files had a header
+    export               write every key/value pair to stdout as JSON lines
+    import               read JSON lines (as written by export) from stdin and insert them
+    snapshot DEST        write a compacted copy of the store to DEST, safe to run while it's in use
+    restore SNAPSHOT     replace the store with a snapshot
+
+FILE can also be a directory, for a store kept in segments.
+
+Keys and values are printed as text when they're valid UTF-8 and as 0x-prefixed hex otherwise,
+--hex or --base64 prints all of them that way instead - and reads the KEY, VALUE and PREFIX you pass
+that way too, so a key that list printed as 0x... can be given back with --hex.
+
+Exit codes: 0 ok, 1 key not found or verify found damage, 2 bad usage, 3 anything else went wrong,
+4 the store is in use by another process
 ";
 
+/// exit codes, so that scripts can tell what happened without parsing our output
+const EXIT_NOT_FOUND: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+const EXIT_IN_USE: i32 = 4;
+
+/// how big a segment gets before a new one is started, when FILE is a directory
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// how keys and values get printed, and how the ones on the command line are read
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    /// printed as they are if they're valid UTF-8, hex otherwise - and taken from the command line byte for byte
+    Auto,
+    Hex,
+    Base64,
+}
+
+/// one line of export/import
+/// anything that isn't valid UTF-8 is written as base64, and says so - that way text stays readable in the export
+#[derive(Debug, Serialize, Deserialize)]
+struct Line {
+    key: String,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    key_encoding: Encoding,
+    value: String,
+    #[serde(default, skip_serializing_if = "Encoding::is_utf8")]
+    value_encoding: Encoding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl Encoding {
+    fn is_utf8(&self) -> bool {
+        *self == Encoding::Utf8
+    }
+
+    fn encode(bytes: &[u8]) -> (String, Encoding) {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => (text.to_string(), Encoding::Utf8),
+            Err(_) => (BASE64.encode(bytes), Encoding::Base64),
+        }
+    }
+
+    fn decode(self, text: String) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Utf8 => Ok(text.into_bytes()),
+            Encoding::Base64 => BASE64.decode(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// what to do, worked out (and checked) before the store gets opened -
+/// a typo shouldn't leave a brand new store behind
+#[derive(Debug)]
+enum Command {
+    Get(Vec<u8>),
+    Delete(Vec<u8>),
+    Insert(Vec<u8>, Vec<u8>),
+    Update(Vec<u8>, Vec<u8>),
+    List,
+    Scan(Vec<u8>),
+    Stats,
+    Compact,
+    Verify,
+    Recover,
+    Migrate,
+    Export,
+    Import,
+    Snapshot(PathBuf),
+    Restore(PathBuf),
+}
+
+impl Command {
+    /// Err is what to tell the user - an unknown command or the wrong number of arguments gets the usage instead
+    fn parse(action: &OsStr, args: &[OsString], output: Output) -> Result<Command, String> {
+        let action = action.to_str().unwrap_or_else(|| usage());
+        let bytes = |arg: &OsString| input(arg, output);
+        Ok(match (action, args) {
+            ("get", [key]) => Command::Get(bytes(key)?),
+            ("delete", [key]) => Command::Delete(bytes(key)?),
+            ("insert", [key, value]) => Command::Insert(bytes(key)?, bytes(value)?),
+            ("update", [key, value]) => Command::Update(bytes(key)?, bytes(value)?),
+            ("list", []) => Command::List,
+            ("scan", [prefix]) => Command::Scan(bytes(prefix)?),
+            ("stats", []) => Command::Stats,
+            ("compact", []) => Command::Compact,
+            ("verify", []) => Command::Verify,
+            ("recover", []) => Command::Recover,
+            ("migrate", []) => Command::Migrate,
+            ("export", []) => Command::Export,
+            ("import", []) => Command::Import,
+            ("snapshot", [dest]) => Command::Snapshot(PathBuf::from(dest)),
+            ("restore", [snapshot]) => Command::Restore(PathBuf::from(snapshot)),
+            _ => usage(),
+        })
+    }
+
+    fn read_only(&self) -> bool {
+        matches!(self, Command::Get(_) | Command::List | Command::Scan(_) | Command::Stats | Command::Export | Command::Snapshot(_))
+    }
+}
+
 fn main() {
     //collect and unpack args
-    let args: Vec<String> = std::env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
-    // the reason we need .as_ref() here is because we're treating the next 2 values as slices later on - ie they have to be refs to original
-    let action = args.get(2).expect(&USAGE).as_ref();
-    let key = args.get(3).expect(&USAGE).as_ref();
-    let maybe_value = args.get(4);
-    println!("Passed in: {:?} {:?} {:?} {:?}", fname, action, key, maybe_value);
+    // args_os() rather than args(), which panics on anything that isn't valid UTF-8 - keys don't have to be
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    let output = match args.first().and_then(|arg| arg.to_str()) {
+        Some("--hex") => Output::Hex,
+        Some("--base64") => Output::Base64,
+        _ => Output::Auto,
+    };
+    if !matches!(output, Output::Auto) {
+        args.remove(0);
+    }
+    if args.len() < 2 {
+        usage();
+    }
 
     //Pathbuf is akin to String
     //Path is akin to str (slice)
     //one vs the other: https://stackoverflow.com/questions/32730714/what-is-the-right-way-to-store-an-immutable-path-in-a-struct
     // - Store a PathBuf if you want the struct to own it. If you don't know what you want, start here.
     // - Store a &Path if you just want a reference to a path. Depending on what you're doing, this may be what you want, but if you don't know, it's probably not correct.
-    let path = std::path::Path::new(&fname);
+    let path = Path::new(&args[0]);
+    let command = match Command::parse(&args[1], &args[2..], output) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(EXIT_USAGE);
+        },
+    };
+
+    let code = match run(path, command, output) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            if e.kind() == io::ErrorKind::WouldBlock { EXIT_IN_USE } else { EXIT_ERROR }
+        },
+    };
+    process::exit(code);
+}
+
+fn usage() -> ! {
+    eprintln!("{}{}", USAGE, COMMANDS);
+    process::exit(EXIT_USAGE);
+}
+
+/// read-only opens share the store with each other, a writer has it all to itself
+fn open(path: &Path, read_only: bool) -> io::Result<ActionKV> {
+    let options = Options {
+        max_segment_size: if path.is_dir() { Some(SEGMENT_SIZE) } else { None },
+        read_only,
+        ..Options::default()
+    };
+    ActionKV::open_with(path, options)
+}
+
+fn run(path: &Path, command: Command, output: Output) -> io::Result<i32> {
+    // these have to work on stores that load() would refuse, so they come before it
+    match &command {
+        Command::Verify => return verify(path),
+        Command::Recover => {
+            let report = open(path, false)?.recover()?;
+            println!("skipped {} damaged record(s), cut off {} byte(s)", report.skipped.len(), report.truncated_bytes);
+            if let Some(quarantine) = report.quarantine {
+                println!("damaged records copied to {}", quarantine.display());
+            }
+            return Ok(0);
+        },
+        Command::Migrate => {
+            println!("migrated {} file(s)", ActionKV::migrate(path)?);
+            return Ok(0);
+        },
+        Command::Restore(snapshot) => {
+            ActionKV::restore(snapshot, path)?;
+            println!("restored!");
+            return Ok(0);
+        },
+        _ => {},
+    }
 
     // create an instance of the store = 2 steps:
     // 1 open store = opens the file + creates an empty index
-    let mut store = ActionKV::open(path).expect("failed to open file");
+    let mut store = open(path, command.read_only())?;
     // 2 load store = populates the index with all KV pairs
-    store.load().expect("failed to load data");
-
-    match action {
-        "get" => {
-            match store.get(key).unwrap() {
-                None => eprintln!("{:?} not found", key),
-                //todo in theory shouldn't be converting to String, as string is only valid utf-8, and we intentionally accept any bytes
-                Some(v) => println!("{:?}", String::from_utf8(v)),
+    store.load()?;
+
+    let code = match command {
+        Command::Get(key) => match store.get(&key)? {
+            None => {
+                eprintln!("{} not found", format(&key, output));
+                EXIT_NOT_FOUND
+            },
+            Some(value) => {
+                println!("{}", format(&value, output));
+                0
+            },
+        },
+        Command::Delete(key) => {
+            store.delete(&key)?;
+            println!("deleted!");
+            0
+        },
+        Command::Insert(key, value) => {
+            store.insert(&key, &value)?;
+            println!("inserted!");
+            0
+        },
+        Command::Update(key, value) => {
+            store.update(&key, &value)?;
+            println!("updated!");
+            0
+        },
+        Command::List => {
+            // range() hands them out in key order whatever the index, so the output is stable between runs
+            let mut out = BufWriter::new(io::stdout().lock());
+            for key in store.range(..).keys() {
+                writeln!(out, "{}", format(&key?, output))?;
+            }
+            out.flush()?;
+            0
+        },
+        Command::Scan(prefix) => {
+            let mut out = BufWriter::new(io::stdout().lock());
+            for kv in store.scan_prefix(&prefix) {
+                let kv = kv?;
+                writeln!(out, "{}\t{}", format(&kv.key, output), format(&kv.value, output))?;
             }
+            out.flush()?;
+            0
         },
-        "delete" => {
-            store.delete(key).unwrap();
-            println!("deleted!")
+        Command::Stats => {
+            let stats = store.stats()?;
+            println!("segments:     {}", stats.segments);
+            println!("records:      {}", stats.records);
+            println!("live records: {}", stats.live_records);
+            println!("live bytes:   {}", stats.live_bytes);
+            println!("dead bytes:   {}", stats.dead_bytes);
+            0
         },
-        "insert" => {
-            //delay unwraping the maybe_value till here, till we know we need it
-            let v = maybe_value.expect("&USAGE").as_ref();
-            store.insert(key, v).unwrap();
-            println!("inserted!")
+        Command::Compact => {
+            let before = store.stats()?;
+            store.compact()?;
+            let after = store.stats()?;
+            let reclaimed = (before.live_bytes + before.dead_bytes).saturating_sub(after.live_bytes + after.dead_bytes);
+            println!("compacted, reclaimed {} byte(s)", reclaimed);
+            0
         },
-        "update" => {
-            let v = maybe_value.expect("&USAGE").as_ref();
-            store.update(key, v).unwrap();
-            println!("updated!")
+        Command::Export => {
+            export(&store)?;
+            0
+        },
+        Command::Import => {
+            let count = import(&store)?;
+            println!("imported {} record(s)", count);
+            0
+        },
+        Command::Snapshot(dest) => {
+            let covered = store.snapshot(&dest)?;
+            println!("snapshot taken up to segment {} offset {}", covered.segment, covered.offset);
+            0
+        },
+        Command::Verify | Command::Recover | Command::Migrate | Command::Restore(_) => unreachable!("handled before loading"),
+    };
+
+    // leaves a hint file behind so the next run doesn't have to read the whole data file to rebuild the index
+    store.close()?;
+    Ok(code)
+}
+
+fn verify(path: &Path) -> io::Result<i32> {
+    let damaged = open(path, true)?.verify()?;
+    if damaged.is_empty() {
+        println!("ok, no damaged records");
+        return Ok(0);
+    }
+    for corruption in &damaged {
+        println!("{}", corruption);
+    }
+    println!("{} damaged record(s) - see the recover command", damaged.len());
+    Ok(EXIT_NOT_FOUND)
+}
+
+fn export(store: &ActionKV) -> io::Result<()> {
+    let mut out = BufWriter::new(io::stdout().lock());
+    for kv in store.iter() {
+        let kv = kv?;
+        let (key, key_encoding) = Encoding::encode(&kv.key);
+        let (value, value_encoding) = Encoding::encode(&kv.value);
+        let line = Line { key, key_encoding, value, value_encoding };
+        serde_json::to_writer(&mut out, &line)?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+fn import(store: &ActionKV) -> io::Result<u64> {
+    let mut count = 0;
+    for (number, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // say which line was bad, nobody wants to go hunting through a big export for it
+        let line: Line = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e)))?;
+        let key = line.key_encoding.decode(line.key)?;
+        let value = line.value_encoding.decode(line.value)?;
+        store.insert(&key, &value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// bytes as text, falling back to hex when they aren't valid UTF-8 (unless told otherwise)
+fn format(bytes: &[u8], output: Output) -> String {
+    match output {
+        Output::Auto => match std::str::from_utf8(bytes) {
+            Ok(text) => text.to_string(),
+            Err(_) => format!("0x{}", hex(bytes)),
         },
-        _ => eprintln!("{}", &USAGE),
+        Output::Hex => hex(bytes),
+        Output::Base64 => BASE64.encode(bytes),
     }
+}
 
+/// a key, value or prefix from the command line - the reverse of format()
+fn input(arg: &OsStr, output: Output) -> Result<Vec<u8>, String> {
+    match output {
+        // on unix that's exactly the bytes that were passed in
+        Output::Auto => Ok(arg.as_encoded_bytes().to_vec()),
+        Output::Hex => {
+            let text = arg.to_str().ok_or_else(|| format!("{:?} isn't hex", arg))?;
+            // list without --hex prints 0x in front, that's fine too
+            let digits = text.strip_prefix("0x").unwrap_or(text);
+            if digits.len() % 2 != 0 {
+                return Err(format!("{:?} isn't hex", text));
+            }
+            (0..digits.len())
+                .step_by(2)
+                .map(|i| digits.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| format!("{:?} isn't hex", text))
+        },
+        Output::Base64 => {
+            let text = arg.to_str().ok_or_else(|| format!("{:?} isn't base64", arg))?;
+            BASE64.decode(text).map_err(|e| format!("{:?} isn't base64: {}", text, e))
+        },
+    }
 }
 
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
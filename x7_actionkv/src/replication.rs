@@ -0,0 +1,677 @@
+//! replication: the log of records is the store, so keeping a second copy of it (a hot standby) is a matter of
+//! shipping every record the leader writes to a follower and replaying it there
+//!
+//! ActionKV::subscribe() tails the log from a given offset, handing back each write as a Change once it's on disk
+//! (a batch only once its commit marker is - a follower never sees half of one)
+//! lead() and ActionKV::follow() put that on the wire, for keeping a standby in sync over a socket
+//!
+//! offsets point into the data files, so compact() - which rewrites them - invalidates them
+//! a subscription that gets caught by one fails with Compacted, and has to start over from LogOffset::START
+//! (which, right after a compaction, is a snapshot of every live key)
+//! a follower reconnecting tells the leader where it got to, so that's the only time it gets sent everything again -
+//! a follower kept in files also writes that down next to its store (eg dbs/store.follow), so a restart resumes too
+
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::format::FILE_HEADER_LEN;
+use crate::batch::{Batches, Framed};
+use crate::segment::{OwnedReader, Segment, SegmentReader};
+use crate::{ActionKV, ByteString, Corruption, Options, Record, WriteBatch};
+
+/// bumped whenever the frames lead() sends change shape
+const PROTOCOL_VERSION: u32 = 2;
+
+/// how long lead() lets a quiet connection go before telling the follower it's still there
+const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// a place in the log - which segment, and where in it
+/// only means something to the store that handed it out, and only until its next compact() -
+/// generation says which compaction it's from, subscribing from one that's older than the store's fails with Compacted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LogOffset {
+    pub segment: u32,
+    pub offset: u64,
+    pub generation: u64,
+}
+
+impl LogOffset {
+    /// the very first record, whichever segment that's in - good in any generation
+    pub const START: LogOffset = LogOffset { segment: 0, offset: 0, generation: 0 };
+}
+
+/// one write, as it went into the leader
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    /// expires_at is milliseconds since the unix epoch, for writes made with insert_with_ttl()
+    Insert { key: ByteString, value: ByteString, expires_at: Option<u64> },
+    Delete { key: ByteString },
+    Batch(WriteBatch),
+}
+
+/// a Change, plus where to subscribe from to pick up after it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub change: Change,
+    pub next: LogOffset,
+}
+
+/// the log a subscription (or an iter()) was reading got rewritten by compact() - subscribe again from LogOffset::START
+/// handed back wrapped in an io::Error - Compacted::is() tells it apart from the rest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compacted {
+    /// how far the subscription or iterator had got
+    pub at: LogOffset,
+}
+
+impl fmt::Display for Compacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "segment {} was compacted while reading it at offset {}", self.at.segment, self.at.offset)
+    }
+}
+
+impl error::Error for Compacted {}
+
+impl From<Compacted> for io::Error {
+    fn from(e: Compacted) -> Self {
+        io::Error::other(e)
+    }
+}
+
+impl Compacted {
+    /// whether e is a Compacted
+    pub fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|e| e.is::<Compacted>())
+    }
+}
+
+/// lets subscriptions sleep until there's something new in the log, instead of polling the files
+/// bumped by every append and compaction
+#[derive(Debug, Default)]
+pub(crate) struct Tail {
+    version: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl Tail {
+    pub(crate) fn bump(&self) {
+        *self.version.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    fn version(&self) -> u64 {
+        *self.version.lock().unwrap()
+    }
+
+    /// waits until the version moves on from seen, or the deadline passes - false means it timed out
+    fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let mut version = self.version.lock().unwrap();
+        while *version == seen {
+            match deadline {
+                None => version = self.changed.wait(version).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    version = self.changed.wait_timeout(version, deadline - now).unwrap().0;
+                },
+            }
+        }
+        true
+    }
+}
+
+/// returned by ActionKV::subscribe() - every write from some point in the log onwards, in the order they were made
+/// holds on to the segment it's reading, so it can finish it even if compact() swaps it out in the meantime
+pub struct Subscription<'a> {
+    store: &'a ActionKV,
+    id: u32,
+    segment: Arc<Segment>,
+    /// where the next record starts
+    offset: u64,
+    /// let go of whenever we get to the end of what's been written so far, it may have read half a record
+    reader: Option<BufReader<OwnedReader>>,
+    /// holds on to the writes of a batch until its commit marker turns up
+    batches: Batches<(ByteString, Option<ByteString>)>,
+    /// the store's generation when we last made sure segment was still its own - what position() hands out
+    generation: u64,
+}
+
+impl<'a> Subscription<'a> {
+    pub(crate) fn new(store: &'a ActionKV, from: LogOffset) -> io::Result<Self> {
+        let state = store.state();
+        let (id, segment) = if from == LogOffset::START {
+            let (id, segment) = state.segments.iter().next().expect("there's always at least one segment");
+            (*id, segment)
+        } else {
+            match state.segments.get(&from.segment) {
+                // a compaction since then has moved the records around, the offset might be anywhere in the middle of one
+                _ if from.generation != state.generation => return Err(Compacted { at: from }.into()),
+                // a merged segment doesn't have the offsets any of the ones it replaced had
+                Some(segment) if segment.first == from.segment => (from.segment, segment),
+                _ => return Err(Compacted { at: from }.into()),
+            }
+        };
+        let offset = from.offset.max(FILE_HEADER_LEN);
+        if offset > segment.len()? {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("offset {} is past the end of segment {}", offset, id)));
+        }
+        Ok(Subscription { store, id, segment: Arc::clone(segment), offset, reader: None, batches: Batches::new(), generation: state.generation })
+    }
+
+    /// where the next event will come from - subscribe from here to carry on where this one left off
+    /// part way through a batch that's wherever it started, so it gets replayed in full
+    pub fn position(&self) -> LogOffset {
+        let offset = self.batches.open_at().unwrap_or(self.offset);
+        LogOffset { segment: self.id, offset, generation: self.generation }
+    }
+
+    /// the next write, or None if we've caught up with the log
+    pub fn try_next(&mut self) -> io::Result<Option<Event>> {
+        loop {
+            if self.offset < self.segment.len()? {
+                let (segment, offset) = (&self.segment, self.offset);
+                let reader = self.reader.get_or_insert_with(|| BufReader::new(SegmentReader::new(Arc::clone(segment), offset)));
+                let record = match ActionKV::process_record(reader) {
+                    Ok(record) => record,
+                    // the writer is part way through appending it - it'll be whole next time we look
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        self.reader = None;
+                        return Ok(None);
+                    },
+                    Err(e) => return Err(Corruption::locate(e, self.id, self.offset)),
+                };
+                let start = self.offset;
+                self.offset += record.len;
+                if let Some(change) = self.change(record, start) {
+                    return Ok(Some(Event { change, next: self.position() }));
+                }
+                continue;
+            }
+            self.reader = None;
+
+            let state = self.store.state();
+            match state.segments.range(self.id + 1..).next() {
+                Some((id, next)) => {
+                    // sealing happens after the last append to a segment, so now that there's a newer one its length is final
+                    if self.offset < self.segment.len()? {
+                        continue;
+                    }
+                    // the next segment is a compaction of ones we haven't read yet - whatever they said about deletes is gone
+                    if next.first <= self.id {
+                        return Err(Compacted { at: self.position() }.into());
+                    }
+                    self.id = *id;
+                    self.segment = Arc::clone(next);
+                    self.offset = FILE_HEADER_LEN;
+                    self.batches.clear();
+                    self.generation = state.generation;
+                },
+                None => {
+                    // the file we've been reading isn't the store's any more
+                    let current = state.segments.get(&self.id);
+                    if !current.is_some_and(|current| Arc::ptr_eq(current, &self.segment)) {
+                        return Err(Compacted { at: self.position() }.into());
+                    }
+                    // still the same file, so the offsets we've got to are as good in a newer generation as in the one we started in
+                    self.generation = state.generation;
+                    return Ok(None);
+                },
+            }
+        }
+    }
+
+    /// the next write, waiting for one if we've caught up - None if there still isn't one once timeout has passed
+    /// no timeout waits for as long as it takes
+    pub fn wait_next(&mut self, timeout: Option<Duration>) -> io::Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // taken before looking, so a write landing right after we've looked still wakes us up
+            let seen = self.store.tail.version();
+            if let Some(event) = self.try_next()? {
+                return Ok(Some(event));
+            }
+            if !self.store.tail.wait(seen, deadline) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// turns a record into the change it stands for - same rules as load(), so batches only count once they're committed
+    fn change(&mut self, record: Record, start: u64) -> Option<Change> {
+        let batched = |record: Record| {
+            let value = if record.is_tombstone() { None } else { Some(record.kv.value) };
+            (record.kv.key, value)
+        };
+        match self.batches.next(record, start, batched) {
+            Framed::Single(record) if record.is_tombstone() => Some(Change::Delete { key: record.kv.key }),
+            Framed::Single(record) => Some(Change::Insert { key: record.kv.key, value: record.kv.value, expires_at: record.expires_at }),
+            Framed::Committed(ops) => Some(Change::Batch(WriteBatch { ops })),
+            Framed::Held => None,
+        }
+    }
+}
+
+/// what goes over the wire between lead() and follow() - each one a little endian u32 length followed by that many bytes of bincode
+/// the leader says Hello, the follower answers with Resume, and from then on it's the leader doing the talking
+#[derive(Debug, Serialize, Deserialize)]
+enum Frame {
+    /// always comes first
+    Hello { version: u32 },
+    /// where the follower got to last time, None if it has nothing from us yet
+    Resume { from: Option<LogOffset> },
+    /// the log is about to be replayed from the start - the follower should forget whatever isn't in it
+    Reset,
+    Event(Event),
+    /// nothing more to send for now, also sent every so often while there's nothing to say
+    /// at is where to resume from once everything so far has been applied
+    CaughtUp { at: LogOffset },
+}
+
+fn write_frame<W: Write>(w: &mut W, frame: &Frame) -> io::Result<()> {
+    let bytes = bincode::serialize(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let len = u32::try_from(bytes.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "change too big to replicate"))?;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&bytes)
+}
+
+/// None means the connection was closed cleanly between frames
+fn read_frame<R: Read>(r: &mut R) -> io::Result<Option<Frame>> {
+    let mut len = [0; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_le_bytes(len) as u64;
+    // never trust a length enough to allocate it up front
+    let mut bytes = Vec::with_capacity(len.min(64 * 1024) as usize);
+    r.by_ref().take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    bincode::deserialize(&bytes).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// streams every write made to store into stream, for ActionKV::follow() to replay on the other end
+/// picks up wherever the follower says it got to, and then keeps going as new writes come in,
+/// so it only returns once something goes wrong (the follower hanging up, usually)
+/// the whole log is only sent when the follower has nothing yet, or a compaction on our side made its offset stale
+pub fn lead<S: Read + Write>(store: &ActionKV, stream: S) -> io::Result<()> {
+    let mut w = BufWriter::new(stream);
+    write_frame(&mut w, &Frame::Hello { version: PROTOCOL_VERSION })?;
+    w.flush()?;
+    let from = match read_frame(w.get_mut())? {
+        Some(Frame::Resume { from }) => from,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "follower didn't say where to resume from")),
+    };
+
+    let mut subscription = match from.map(|from| store.subscribe(from)) {
+        Some(Ok(subscription)) => subscription,
+        Some(Err(e)) if !Compacted::is(&e) => return Err(e),
+        // nothing to resume from, or nothing that still means anything
+        _ => replay(store, &mut w)?,
+    };
+    loop {
+        let event = match subscription.try_next() {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                // everything we have is sent, so flush before waiting around for more
+                write_frame(&mut w, &Frame::CaughtUp { at: subscription.position() })?;
+                w.flush()?;
+                match subscription.wait_next(Some(HEARTBEAT)) {
+                    Ok(Some(event)) => event,
+                    Ok(None) => continue,
+                    Err(e) if Compacted::is(&e) => {
+                        subscription = replay(store, &mut w)?;
+                        continue;
+                    },
+                    Err(e) => return Err(e),
+                }
+            },
+            Err(e) if Compacted::is(&e) => {
+                subscription = replay(store, &mut w)?;
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        write_frame(&mut w, &Frame::Event(event))?;
+    }
+}
+
+/// tells the follower to start over, and hands back a subscription to the whole log for it
+fn replay<'a, W: Write>(store: &'a ActionKV, w: &mut W) -> io::Result<Subscription<'a>> {
+    write_frame(w, &Frame::Reset)?;
+    store.subscribe(LogOffset::START)
+}
+
+impl ActionKV {
+    /// every write from `from` onwards, see Subscription
+    /// LogOffset::START replays the whole log, log_end() only picks up writes made from now on
+    pub fn subscribe(&self, from: LogOffset) -> io::Result<Subscription<'_>> {
+        Subscription::new(self, from)
+    }
+
+    /// where the next write will go
+    pub fn log_end(&self) -> io::Result<LogOffset> {
+        let state = self.state();
+        Ok(LogOffset { segment: state.active_id(), offset: state.active().len()?, generation: state.generation })
+    }
+
+    /// makes a change someone else made (to another store, usually) to this one
+    pub fn apply(&self, change: &Change) -> io::Result<()> {
+        match change {
+            Change::Insert { key, value, expires_at } => self.insert_expiring(key, value, *expires_at),
+            Change::Delete { key } => self.delete(key),
+            Change::Batch(batch) => self.write(batch),
+        }
+    }
+
+    /// keeps this store in sync with the leader at the other end of stream (see lead()) until the connection ends
+    /// position is how far we've got - pass None the first time, and the same one again when reconnecting,
+    /// so the leader only sends what we haven't seen yet
+    /// a store kept in files saves it (in store.follow) every time the leader has caught us up, once what came before is synced -
+    /// None then means carry on from there, so a restarted follower doesn't get sent everything again
+    ///
+    /// when the leader can't carry on from there (a compaction on its side) it replays its whole log instead
+    /// that goes into a staging store next to this one first, and only once the leader has caught up is it copied over -
+    /// keys that have changed are written, keys the leader no longer has are deleted, and the store is compacted afterwards
+    /// until then the standby keeps serving what it had, and a replay that gets cut short leaves no trace
+    /// nothing stops other writes to this store, but they'll be overwritten or deleted the next time the leader starts over
+    pub fn follow<S: Read + Write>(&self, stream: S, position: &mut Option<LogOffset>) -> io::Result<()> {
+        let mut r = BufReader::new(stream);
+        match read_frame(&mut r)? {
+            Some(Frame::Hello { version }) if version == PROTOCOL_VERSION => {},
+            Some(Frame::Hello { version }) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("leader speaks replication protocol version {}, we speak {}", version, PROTOCOL_VERSION)));
+            },
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "leader didn't say hello")),
+        }
+        let followed_path = self.aux_path("follow");
+        if let (None, Some(path)) = (*position, &followed_path) {
+            *position = read_followed(path)?;
+        }
+        write_frame(r.get_mut(), &Frame::Resume { from: *position })?;
+        r.get_mut().flush()?;
+
+        // the leader's replay, while one is under way
+        let mut staging: Option<Staging> = None;
+        // what's in store.follow, as far as this connection knows
+        let mut saved = None;
+        loop {
+            let frame = match read_frame(&mut r)? {
+                Some(frame) => frame,
+                None => return Ok(()),
+            };
+            match frame {
+                Frame::Hello { .. } => return Err(io::Error::new(io::ErrorKind::InvalidData, "leader said hello twice")),
+                Frame::Resume { .. } => return Err(io::Error::new(io::ErrorKind::InvalidData, "leader asked us where to resume from")),
+                Frame::Reset => {
+                    // a replay that was still going is no good any more - it has to be gone before the new one reuses its file
+                    drop(staging.take());
+                    staging = Some(Staging::new(self)?);
+                },
+                Frame::Event(event) => match staging.as_ref() {
+                    Some(staging) => staging.apply(&event.change)?,
+                    None => {
+                        self.apply(&event.change)?;
+                        *position = Some(event.next);
+                    },
+                },
+                Frame::CaughtUp { at } => {
+                    if let Some(staging) = staging.take() {
+                        self.take_over(&staging)?;
+                    }
+                    // heartbeats repeat the same offset, no need to write it down again
+                    if let (Some(path), true) = (&followed_path, saved != Some(at)) {
+                        // the changes have to be on disk before the offset that says they're applied
+                        self.sync()?;
+                        write_followed(path, at)?;
+                        saved = Some(at);
+                    }
+                    *position = Some(at);
+                },
+            }
+        }
+    }
+
+    /// makes this store hold exactly what replayed does
+    fn take_over(&self, replayed: &ActionKV) -> io::Result<()> {
+        let mut written = false;
+        let mut records = replayed.iter();
+        while let Some(record) = records.next_record_live() {
+            let record = record?;
+            // rewriting a key that hasn't changed would only give compact() more to do
+            if !self.holds(&record)? {
+                self.insert_expiring(&record.kv.key, &record.kv.value, record.expires_at)?;
+                written = true;
+            }
+        }
+        // found first and deleted afterwards, rather than writing to the store while reading through it
+        let mut stale = vec![];
+        for key in self.keys() {
+            let key = key?;
+            if !replayed.contains_key(&key)? {
+                stale.push(key);
+            }
+        }
+        for key in &stale {
+            self.delete(key)?;
+        }
+        // whatever the replay replaced is dead weight from here on
+        if written || !stale.is_empty() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// whether record's key already has the same value and expiry here
+    fn holds(&self, record: &Record) -> io::Result<bool> {
+        let (position, segment) = {
+            let state = self.state();
+            match state.lookup(&record.kv.key)? {
+                Some(position) => (position, Arc::clone(state.segment(position.segment)?)),
+                None => return Ok(false),
+            }
+        };
+        let current = ActionKV::read_record(&segment, position)?;
+        Ok(current.kv.value == record.kv.value && current.expires_at == record.expires_at)
+    }
+}
+
+/// the offset follow() saved last time, None if it never got caught up
+/// kept as text, "segment offset generation", like the generation file
+fn read_followed(path: &Path) -> io::Result<Option<LogOffset>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{} doesn't hold a log offset", path.display()));
+    let mut numbers = text.split_whitespace();
+    let mut next = || numbers.next().ok_or_else(invalid);
+    let segment = next()?.parse().map_err(|_| invalid())?;
+    let offset = next()?.parse().map_err(|_| invalid())?;
+    let generation = next()?.parse().map_err(|_| invalid())?;
+    Ok(Some(LogOffset { segment, offset, generation }))
+}
+
+fn write_followed(path: &Path, at: LogOffset) -> io::Result<()> {
+    ActionKV::write_atomically(path, format!("{} {} {}\n", at.segment, at.offset, at.generation).as_bytes())
+}
+
+/// where follow() puts a replay of the leader's log while it's under way - a file next to the store (removed again afterwards),
+/// or memory for a store that isn't kept in files
+struct Staging {
+    /// only None while being dropped
+    store: Option<ActionKV>,
+    path: Option<PathBuf>,
+}
+
+impl Staging {
+    fn new(store: &ActionKV) -> io::Result<Self> {
+        let path = store.aux_path("staging");
+        let staging = match &path {
+            Some(path) => {
+                // whatever a replay that got cut short left behind goes first
+                let f = OpenOptions::new().read(true).create(true).append(true).open(path)?;
+                f.set_len(0)?;
+                ActionKV::with_storage(f, Options::default())?
+            },
+            None => ActionKV::in_memory(),
+        };
+        Ok(Staging { store: Some(staging), path })
+    }
+}
+
+impl Deref for Staging {
+    type Target = ActionKV;
+
+    fn deref(&self) -> &ActionKV {
+        self.store.as_ref().expect("only taken while dropping")
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        // closed before it's removed, windows won't remove a file that's still open
+        drop(self.store.take());
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::path::Path;
+    use std::thread;
+
+    use super::*;
+    use crate::ByteStr;
+
+    fn keys(store: &ActionKV) -> Vec<ByteString> {
+        store.keys().collect::<io::Result<_>>().unwrap()
+    }
+
+    fn key_of(event: &Event) -> &ByteStr {
+        match &event.change {
+            Change::Insert { key, .. } | Change::Delete { key } => key,
+            Change::Batch(_) => panic!("didn't write a batch"),
+        }
+    }
+
+    #[test]
+    fn a_subscription_carries_on_from_where_an_event_says() {
+        let store = ActionKV::in_memory();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.delete(b"a").unwrap();
+
+        let mut subscription = store.subscribe(LogOffset::START).unwrap();
+        let first = subscription.try_next().unwrap().unwrap();
+        assert_eq!(first.change, Change::Insert { key: b"a".to_vec(), value: b"1".to_vec(), expires_at: None });
+
+        let mut resumed = store.subscribe(first.next).unwrap();
+        assert_eq!(key_of(&resumed.try_next().unwrap().unwrap()), b"b");
+        assert_eq!(resumed.try_next().unwrap().unwrap().change, Change::Delete { key: b"a".to_vec() });
+        assert!(resumed.try_next().unwrap().is_none());
+        assert_eq!(resumed.position(), store.log_end().unwrap());
+
+        // writes made after catching up still turn up
+        store.insert(b"c", b"3").unwrap();
+        assert_eq!(key_of(&resumed.try_next().unwrap().unwrap()), b"c");
+    }
+
+    #[test]
+    fn offsets_from_before_a_compaction_are_compacted() {
+        let store = ActionKV::in_memory();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"a", b"2").unwrap();
+        let end = store.log_end().unwrap();
+        let mut open = store.subscribe(LogOffset::START).unwrap();
+        while open.try_next().unwrap().is_some() {}
+
+        store.compact().unwrap();
+        assert!(Compacted::is(&store.subscribe(end).err().expect("end is stale")));
+        // a subscription part way through finds out the next time it looks
+        assert!(Compacted::is(&open.try_next().unwrap_err()));
+        // the start is good in any generation
+        let mut fresh = store.subscribe(LogOffset::START).unwrap();
+        assert_eq!(fresh.try_next().unwrap().unwrap().change, Change::Insert { key: b"a".to_vec(), value: b"2".to_vec(), expires_at: None });
+    }
+
+    /// runs follow() against lead() until the leader has caught the follower up
+    fn follow_once(leader: &ActionKV, follower: &ActionKV, position: &mut Option<LogOffset>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let (stream, _) = listener.accept().unwrap();
+                // only ends once we've hung up
+                let _ = lead(leader, stream);
+            });
+            let stream = TcpStream::connect(address).unwrap();
+            // after catching up there's nothing more until the next heartbeat, which is our cue to hang up
+            stream.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+            let hang_up = stream.try_clone().unwrap();
+            let e = follower.follow(stream, position).unwrap_err();
+            assert!(matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{}", e);
+            hang_up.shutdown(Shutdown::Both).unwrap();
+        });
+    }
+
+    fn opened(path: &Path) -> ActionKV {
+        let mut store = ActionKV::open(path).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    #[test]
+    fn a_follower_replays_then_resumes_then_starts_over_after_a_compaction() {
+        let dir = std::env::temp_dir().join(format!("akv-follow-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("follower");
+        let leader = ActionKV::in_memory();
+        leader.insert(b"a", b"1").unwrap();
+        leader.insert(b"b", b"2").unwrap();
+        leader.delete(b"a").unwrap();
+
+        // the first time round the whole log is replayed, and the follower ends up holding exactly what the leader does
+        let follower = opened(&path);
+        follower.insert(b"x", b"not the leader's").unwrap();
+        let mut position = None;
+        follow_once(&leader, &follower, &mut position);
+        assert_eq!(keys(&follower), vec![b"b".to_vec()]);
+        assert_eq!(position, Some(leader.log_end().unwrap()));
+        assert!(!ActionKV::aux_path_of(&path, false, "staging").exists());
+        drop(follower);
+
+        // a restart picks up from the position saved next to the store - a replay would get rid of y again
+        leader.insert(b"c", b"3").unwrap();
+        let follower = opened(&path);
+        follower.insert(b"y", b"the follower's own").unwrap();
+        let mut position = None;
+        follow_once(&leader, &follower, &mut position);
+        assert_eq!(keys(&follower), vec![b"b".to_vec(), b"c".to_vec(), b"y".to_vec()]);
+
+        // after a compaction that position means nothing any more, so the leader starts over and take_over() tidies up
+        leader.compact().unwrap();
+        follow_once(&leader, &follower, &mut position);
+        assert_eq!(keys(&follower), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(follower.get(b"c").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(position, Some(leader.log_end().unwrap()));
+        drop(follower);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! iterators over what's in the store, reading each value from disk only once it's asked for
+
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::{io, vec};
+
+use crate::segment::{OwnedReader, Segment, SegmentReader};
+use crate::{ActionKV, ByteStr, ByteString, Compacted, KeyValuePair, LogOffset};
+
+/// returned by ActionKV::range() and ActionKV::scan_prefix()
+/// the index is only locked while looking up the next key, so writes made part way through may or may not show up
+pub struct Range<'a> {
+    store: &'a ActionKV,
+    keys: Keys,
+    /// reading the keys from disk can fail - handed out by the first next()
+    error: Option<io::Error>,
+}
+
+enum Keys {
+    /// an ordered index is asked for the next key every time, so nothing gets copied up front
+    Ordered { next: Bound<ByteString>, end: Bound<ByteString> },
+    /// a hash index has no order, so the matching keys are collected and sorted before we start
+    Sorted(vec::IntoIter<ByteString>),
+}
+
+impl<'a> Range<'a> {
+    pub(crate) fn new<R: RangeBounds<ByteString>>(store: &'a ActionKV, range: R) -> Self {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        let bounds = (as_ref(&start), as_ref(&end));
+        let mut error = None;
+        let keys = {
+            let state = store.state();
+            match state.index.kind() {
+                crate::IndexKind::Ordered => Keys::Ordered { next: start, end },
+                crate::IndexKind::Hash => {
+                    // the keys are all in memory already - only the matching ones are copied out, and nothing is read from disk
+                    let no_disk = |_| unreachable!("a hash index holds its keys");
+                    let mut matching: Vec<_> = state
+                        .index
+                        .entries(&no_disk)
+                        .filter_map(|entry| entry.ok().map(|(key, _)| key))
+                        .filter(|key| bounds.contains(key.as_slice()))
+                        .collect();
+                    matching.sort();
+                    Keys::Sorted(matching.into_iter())
+                },
+                crate::IndexKind::Fingerprint => {
+                    // a fingerprint index doesn't have the keys, they're streamed back from the data files instead -
+                    // the index isn't locked while we look and only the matching keys are kept
+                    drop(state);
+                    // a compact() part way through just means starting over, the keys are all still there
+                    let mut matching = vec![];
+                    'stream: loop {
+                        matching.clear();
+                        for kv in store.iter() {
+                            match kv {
+                                Ok(kv) if bounds.contains(kv.key.as_slice()) => matching.push(kv.key),
+                                Ok(_) => {},
+                                Err(e) if Compacted::is(&e) => continue 'stream,
+                                Err(e) => {
+                                    error = Some(e);
+                                    matching.clear();
+                                    break 'stream;
+                                },
+                            }
+                        }
+                        break;
+                    }
+                    matching.sort();
+                    Keys::Sorted(matching.into_iter())
+                },
+            }
+        };
+        Range { store, keys, error }
+    }
+
+    fn next_key(&mut self) -> Option<ByteString> {
+        match &mut self.keys {
+            Keys::Ordered { next, end } => {
+                let (found, _) = self.store.state().index.first_in(as_ref(next), as_ref(end))?;
+                *next = Bound::Excluded(found.clone());
+                Some(found)
+            },
+            Keys::Sorted(keys) => keys.next(),
+        }
+    }
+
+    /// just the keys, in the same order - only each key's record header is read (to leave out expired ones), not its value
+    pub fn keys(mut self) -> impl Iterator<Item = io::Result<ByteString>> + 'a {
+        std::iter::from_fn(move || self.next_live_key())
+    }
+
+    fn next_live_key(&mut self) -> Option<io::Result<ByteString>> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            let key = self.next_key()?;
+            match self.store.contains_key(&key) {
+                Ok(true) => return Some(Ok(key)),
+                Ok(false) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+impl Iterator for Range<'_> {
+    type Item = io::Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        loop {
+            // the key is looked up again rather than trusting the position we found it at,
+            // compact() may have moved it since - or a delete() got rid of it, in which case it's skipped
+            let key = self.next_key()?;
+            match self.store.get(&key) {
+                Ok(Some(value)) => return Some(Ok(KeyValuePair { key, value })),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn as_ref(bound: &Bound<ByteString>) -> Bound<&ByteStr> {
+    bound.as_ref().map(|key| key.as_slice())
+}
+
+/// the range holding every key that starts with prefix: prefix <= key < prefix with its last byte bumped by one
+/// trailing 0xff bytes can't be bumped so they're dropped first - a prefix of nothing but 0xff has no upper end at all
+pub(crate) fn prefix_range(prefix: &ByteStr) -> (Bound<ByteString>, Bound<ByteString>) {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix.to_vec()), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix.to_vec()), Bound::Unbounded)
+}
+
+/// returned by ActionKV::iter() - every live key/value pair, in the order they sit on disk
+/// rather than looking each key up, it reads through the data files front to back
+/// and only yields the records the index still points at, so older versions, tombstones and batch markers get skipped
+/// it holds on to the segments as they were when it was created, so a compact() part way through can't pull them out from under it
+/// the records compact() moves aren't where the index says any more though, so rather than quietly skipping live keys
+/// the iterator stops with a Compacted error as soon as it notices - start a new one to read what's there now
+pub struct Iter<'a> {
+    store: &'a ActionKV,
+    /// segments still to go, oldest first
+    segments: vec::IntoIter<(u32, Arc<Segment>)>,
+    /// the segment being read right now, and where in it we are
+    current: Option<(u32, BufReader<OwnedReader>, u64)>,
+    /// the store's generation when we took hold of the segments
+    generation: u64,
+}
+
+impl<'a> Iter<'a> {
+    pub(crate) fn new(store: &'a ActionKV, segments: Vec<(u32, Arc<Segment>)>, generation: u64) -> Self {
+        Iter { store, segments: segments.into_iter(), current: None, generation }
+    }
+
+    fn next_record(&mut self) -> io::Result<Option<(u32, u64, crate::Record)>> {
+        loop {
+            if self.current.is_none() {
+                let (id, segment) = match self.segments.next() {
+                    Some(segment) => segment,
+                    None => return Ok(None),
+                };
+                // records start after the file header
+                let start = crate::format::FILE_HEADER_LEN;
+                self.current = Some((id, BufReader::new(SegmentReader::new(segment, start)), start));
+            }
+            let (id, f, offset) = self.current.as_mut().unwrap();
+
+            if *offset >= f.get_ref().segment().len()? {
+                self.current = None;
+                continue;
+            }
+            let record = ActionKV::process_record(f).map_err(|e| crate::Corruption::locate(e, *id, *offset))?;
+            let found = (*id, *offset, record);
+            *offset += found.2.len;
+            return Ok(Some(found));
+        }
+    }
+
+    /// whether the segment we're reading is still the store's, rather than one compact() has merged away
+    fn reading_current(&self, segments: &BTreeMap<u32, Arc<Segment>>) -> bool {
+        match &self.current {
+            Some((id, f, _)) => segments.get(id).is_some_and(|segment| std::ptr::eq(&**segment, f.get_ref().segment())),
+            None => true,
+        }
+    }
+
+    /// the next live record as a whole, expiry and all - what next() hands out the key and value of
+    pub(crate) fn next_record_live(&mut self) -> Option<io::Result<crate::Record>> {
+        loop {
+            let (id, offset, record) = match self.next_record() {
+                Ok(Some(found)) => found,
+                Ok(None) => return None,
+                Err(e) => {
+                    // no point carrying on past a broken record, we'd only be guessing where the next one starts
+                    self.segments = Vec::new().into_iter();
+                    self.current = None;
+                    return Some(Err(e));
+                },
+            };
+            let position = crate::Position { segment: id, offset, len: record.len };
+            let state = self.store.state();
+            // checked under the same lock as the index, so a compact() can't land in between the two
+            if !self.reading_current(&state.segments) {
+                self.segments = Vec::new().into_iter();
+                self.current = None;
+                return Some(Err(Compacted { at: LogOffset { segment: id, offset, generation: self.generation } }.into()));
+            }
+            let live = state.index.points_at(&record.kv.key, position);
+            // an expired key stays in the index until the next compact(), so it has to be weeded out here
+            if live && !record.is_expired(crate::now_millis()) {
+                return Some(Ok(record));
+            }
+        }
+    }
+}
+
+impl Iterator for Iter<'_> {
+    type Item = io::Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record_live().map(|record| record.map(|record| record.kv))
+    }
+}
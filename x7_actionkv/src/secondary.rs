@@ -0,0 +1,117 @@
+//! secondary indexes: look keys up by something inside their values, eg a user record by its email
+//! each one is a function pulling a field out of a value, plus a field -> keys map kept next to the main index
+//! and updated under the same lock, so the two never disagree
+//!
+//! they live in memory only and are rebuilt from the data files - register them before load(), which then reads
+//! every record instead of using the hint (the hint only has keys and positions, not the values they'd need)
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+
+use crate::{ActionKV, ByteStr, ByteString};
+
+/// pulls the field to index by out of a value - None leaves the record out of the index
+type Extractor = dyn Fn(&ByteStr) -> Option<ByteString> + Send + Sync;
+
+#[derive(Default)]
+pub(crate) struct Secondaries {
+    by_name: BTreeMap<String, Secondary>,
+}
+
+struct Secondary {
+    extract: Box<Extractor>,
+    keys_by_field: HashMap<ByteString, BTreeSet<ByteString>>,
+    /// which field each key is filed under, so it can be taken out again without reading its old value back
+    field_by_key: HashMap<ByteString, ByteString>,
+}
+
+impl Secondary {
+    fn new(extract: Box<Extractor>) -> Self {
+        Secondary { extract, keys_by_field: HashMap::new(), field_by_key: HashMap::new() }
+    }
+
+    fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.remove(key);
+        if let Some(field) = (self.extract)(value) {
+            self.keys_by_field.entry(field.clone()).or_default().insert(key.to_vec());
+            self.field_by_key.insert(key.to_vec(), field);
+        }
+    }
+
+    fn remove(&mut self, key: &ByteStr) {
+        let field = match self.field_by_key.remove(key) {
+            Some(field) => field,
+            None => return,
+        };
+        if let Some(keys) = self.keys_by_field.get_mut(&field) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.keys_by_field.remove(&field);
+            }
+        }
+    }
+}
+
+impl Secondaries {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// key now has value - files it under its new field in every index, and takes it out from under its old one
+    pub(crate) fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        for secondary in self.by_name.values_mut() {
+            secondary.insert(key, value);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &ByteStr) {
+        for secondary in self.by_name.values_mut() {
+            secondary.remove(key);
+        }
+    }
+}
+
+impl fmt::Debug for Secondaries {
+    /// the extractors can't be printed, so it's just the names
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.by_name.keys()).finish()
+    }
+}
+
+impl ActionKV {
+    /// registers a secondary index called name, filing every key under whatever extract() pulls out of its value
+    /// keys already in the store are indexed straight away (which means reading every value), later writes keep it up to date
+    /// registering a name that's already taken replaces that index
+    ///
+    /// eg with json values: `store.add_index("email", |value| serde_json::from_slice::<User>(value).ok().map(|user| user.email.into_bytes()))`
+    pub fn add_index<F>(&self, name: &str, extract: F) -> io::Result<()>
+    where
+        F: Fn(&ByteStr) -> Option<ByteString> + Send + Sync + 'static,
+    {
+        let mut secondary = Secondary::new(Box::new(extract));
+        // no writes while we catch up on what's there, otherwise one could land after we've read past its key
+        let _writer = self.writer.lock().unwrap();
+        for kv in self.iter() {
+            let kv = kv?;
+            secondary.insert(&kv.key, &kv.value);
+        }
+        self.state_mut().secondary.by_name.insert(name.to_string(), secondary);
+        Ok(())
+    }
+
+    /// forgets about the secondary index called name, if there is one
+    pub fn remove_index(&self, name: &str) {
+        self.state_mut().secondary.by_name.remove(name);
+    }
+
+    /// every key whose value has field in the index called name, sorted - NotFound if there's no such index
+    /// like len(), a key whose ttl has run out is still listed until the next compact()
+    pub fn get_by_index(&self, name: &str, field: &ByteStr) -> io::Result<Vec<ByteString>> {
+        let state = self.state();
+        let secondary = state.secondary.by_name.get(name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no secondary index called {}", name))
+        })?;
+        Ok(secondary.keys_by_field.get(field).map(|keys| keys.iter().cloned().collect()).unwrap_or_default())
+    }
+}
@@ -0,0 +1,188 @@
+//! a segmented store keeps its records in a directory of numbered files instead of a single one
+//! only the newest segment is ever appended to - once it's full it gets sealed and a fresh one is started,
+//! so every older segment is immutable and can be copied somewhere for archiving while the store is running
+//!
+//! segments are named after the ids they cover, eg `00000007.seg`
+//! compaction merges a run of segments into one named after the whole run, eg `00000001-00000007.seg`
+//! that merged file replaces all of them - if we crash before the originals are deleted, list() spots them and cleans up
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use memmap2::Mmap;
+
+use crate::Storage;
+
+#[derive(Debug)]
+pub(crate) struct Segment {
+    /// the lowest id this segment stands in for - only differs from its own id once it's the result of compaction
+    pub(crate) first: u32,
+    /// None for a segment that isn't kept in a file
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) f: Arc<dyn Storage>,
+    /// only set up once something reads the segment through a map - see map_covering()
+    map: Mutex<Option<Arc<Mmap>>>,
+}
+
+impl Segment {
+    pub(crate) fn new(first: u32, path: Option<PathBuf>, f: Arc<dyn Storage>) -> Self {
+        Segment { first, path, f, map: Mutex::new(None) }
+    }
+
+    pub(crate) fn len(&self) -> io::Result<u64> {
+        self.f.len()
+    }
+
+    /// reads from offset without moving anything along (pread, for a file), so any number of threads can read at once
+    pub(crate) fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.f.read_at(buf, offset)
+    }
+
+    pub(crate) fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.reader_at(offset).read_exact(buf)
+    }
+
+    /// a read-only map of the file that reaches at least as far as end
+    /// sealed segments are only ever mapped once, the active one is remapped whenever a read goes past the end of the old map
+    /// anyone still holding the old map keeps it - it stays valid, it just doesn't see the newer records
+    pub(crate) fn map_covering(&self, end: u64) -> io::Result<Arc<Mmap>> {
+        let mut map = self.map.lock().unwrap();
+        if let Some(map) = map.as_ref() {
+            if map.len() as u64 >= end {
+                return Ok(Arc::clone(map));
+            }
+        }
+        if self.len()? < end {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // safety: a map goes bad if the file underneath it gets truncated - we only ever append to segments,
+        // and the only thing that cuts them short is recover(), which needs &mut ActionKV, so no map can be in use while it runs
+        // compact() doesn't touch the file either, it renames a new one over it and the old one lives on until its last map is dropped
+        let f = self.f.file().ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "only files can be memory mapped"))?;
+        let fresh = Arc::new(unsafe { Mmap::map(f)? });
+        *map = Some(Arc::clone(&fresh));
+        Ok(fresh)
+    }
+
+    /// a Read that starts at offset and keeps track of its own position
+    pub(crate) fn reader_at(&self, offset: u64) -> SegmentReader<&Segment> {
+        SegmentReader::new(self, offset)
+    }
+}
+
+/// reads a segment front to back with positional reads
+/// S is either a plain &Segment or an Arc<Segment> for a reader that has to outlive the store's lock
+pub(crate) struct SegmentReader<S> {
+    segment: S,
+    offset: u64,
+}
+
+/// a reader that holds on to its segment, for iterators and subscriptions that outlive the state lock
+pub(crate) type OwnedReader = SegmentReader<Arc<Segment>>;
+
+impl<S: Deref<Target = Segment>> SegmentReader<S> {
+    pub(crate) fn new(segment: S, offset: u64) -> Self {
+        SegmentReader { segment, offset }
+    }
+
+    pub(crate) fn segment(&self) -> &Segment {
+        &self.segment
+    }
+}
+
+impl<S: Deref<Target = Segment>> Read for SegmentReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.segment.read_at(buf, self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+pub(crate) const EXTENSION: &str = "seg";
+
+pub(crate) fn file_name(first: u32, last: u32) -> String {
+    if first == last {
+        format!("{:08}.{}", last, EXTENSION)
+    } else {
+        format!("{:08}-{:08}.{}", first, last, EXTENSION)
+    }
+}
+
+/// "00000007.seg" -> (7, 7), "00000001-00000007.seg" -> (1, 7), anything else -> None
+fn parse_file_name(name: &str) -> Option<(u32, u32)> {
+    let stem = name.strip_suffix(EXTENSION)?.strip_suffix('.')?;
+    let mut ids = stem.splitn(2, '-');
+    let first = ids.next()?.parse().ok()?;
+    let last = match ids.next() {
+        Some(last) => last.parse().ok()?,
+        None => first,
+    };
+    if first > last {
+        return None;
+    }
+    Some((first, last))
+}
+
+/// finds every segment in dir, keyed by id (the last id it covers) and returned oldest first
+/// segments made redundant by a compaction that didn't get to delete them are removed here - unless clean_up is false
+/// (a read-only open mustn't change anything on disk), then they're just left out
+pub(crate) fn list(dir: &Path, clean_up: bool) -> io::Result<BTreeMap<u32, (u32, PathBuf)>> {
+    let mut found = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some((first, last)) = entry.file_name().to_str().and_then(parse_file_name) {
+            found.push((first, last, entry.path()));
+        }
+    }
+
+    // widest ranges first, so anything they cover is already claimed by the time we get to it
+    found.sort_by_key(|(first, last, _)| (std::cmp::Reverse(last - first), *last));
+    let mut segments: BTreeMap<u32, (u32, PathBuf)> = BTreeMap::new();
+    for (first, last, path) in found {
+        let covered = segments
+            .iter()
+            .any(|(other_last, (other_first, _))| *other_first <= first && last <= *other_last);
+        if covered {
+            if clean_up {
+                match fs::remove_file(&path) {
+                    // somebody beat us to it, which is just as good
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {},
+                }
+            }
+        } else {
+            segments.insert(last, (first, path));
+        }
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_only_removes_covered_segments_when_it_may_clean_up() {
+        let dir = std::env::temp_dir().join(format!("akv-list-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // what a compaction of 1..=2 leaves behind if it crashes before deleting what it merged
+        for (first, last) in [(1, 2), (1, 1), (2, 2), (3, 3)].iter() {
+            fs::write(dir.join(file_name(*first, *last)), b"").unwrap();
+        }
+        let ids = |segments: BTreeMap<u32, (u32, PathBuf)>| segments.iter().map(|(last, (first, _))| (*first, *last)).collect::<Vec<_>>();
+
+        assert_eq!(ids(list(&dir, false).unwrap()), vec![(1, 2), (3, 3)]);
+        assert!(dir.join(file_name(1, 1)).exists());
+        assert!(dir.join(file_name(2, 2)).exists());
+
+        assert_eq!(ids(list(&dir, true).unwrap()), vec![(1, 2), (3, 3)]);
+        assert!(!dir.join(file_name(1, 1)).exists());
+        assert!(!dir.join(file_name(2, 2)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
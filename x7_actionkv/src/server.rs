@@ -0,0 +1,487 @@
+//! serves an ActionKV over TCP, speaking enough of the redis protocol (RESP) for redis-cli and most redis clients
+//! supported: GET, SET (with EX/PX), DEL, EXISTS, KEYS, SCAN, plus PING and QUIT
+//! every connection gets a thread of its own, they all share the one store
+//!
+//! --replicate ADDRESS also streams every write to followers connecting on ADDRESS,
+//! --follow ADDRESS makes this server a read-only standby of the leader replicating on ADDRESS
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use libactionkv::{lead, ActionKV, Options};
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+Usage:
+    cargo.exe run --bin akv_server -- FILE [ADDRESS] [--replicate ADDRESS | --follow ADDRESS]
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+Usage:
+    cargo run --bin akv_server -- FILE [ADDRESS] [--replicate ADDRESS | --follow ADDRESS]
+";
+
+/// where redis itself listens - and only on localhost, there's no authentication
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6379";
+
+/// how big a segment gets before a new one is started, when FILE is a directory
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// same limit redis has on a single bulk string
+const MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+/// a command with more arguments than this is more likely garbage than anything we support
+const MAX_ARGS: u64 = 1024 * 1024;
+
+/// how many keys SCAN returns per call unless asked for a different COUNT
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// how long a follower waits before trying a leader that went away again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// what we send back - these map straight onto RESP types
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let replicate = take_option(&mut args, "--replicate");
+    let follow = take_option(&mut args, "--follow");
+    if replicate.is_some() && follow.is_some() {
+        panic!("{}", USAGE);
+    }
+    let fname = args.first().expect(USAGE);
+    let address = args.get(1).map(String::as_str).unwrap_or(DEFAULT_ADDRESS);
+    let path = Path::new(fname);
+
+    let options = Options {
+        max_segment_size: if path.is_dir() { Some(SEGMENT_SIZE) } else { None },
+        ..Options::default()
+    };
+    let mut store = ActionKV::open_with(path, options).expect("failed to open store");
+    store.load().expect("failed to load data");
+    // from here on the store is shared between connections, which is fine - everything we need from it takes &self
+    let store = Arc::new(store);
+    let read_only = follow.is_some();
+
+    if let Some(replication_address) = replicate {
+        let store = Arc::clone(&store);
+        let listener = TcpListener::bind(&replication_address).expect("failed to bind replication address");
+        eprintln!("replicating to followers on {}", replication_address);
+        thread::spawn(move || accept_followers(&store, listener));
+    }
+    if let Some(leader) = follow {
+        let store = Arc::clone(&store);
+        eprintln!("following {}", leader);
+        thread::spawn(move || follow_leader(&store, &leader));
+    }
+
+    let listener = TcpListener::bind(address).expect("failed to bind address");
+    eprintln!("serving {} on {}", path.display(), address);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept connection: {}", e);
+                continue;
+            },
+        };
+        let store = Arc::clone(&store);
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            if let Err(e) = serve(&store, stream, read_only) {
+                eprintln!("connection from {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// removes `name VALUE` from args, wherever it is, and hands back VALUE
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let at = args.iter().position(|arg| arg == name)?;
+    if at + 1 >= args.len() {
+        panic!("{}", USAGE);
+    }
+    args.remove(at);
+    Some(args.remove(at))
+}
+
+/// every follower gets a thread of its own, streaming our writes to it for as long as it stays connected
+fn accept_followers(store: &Arc<ActionKV>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("failed to accept follower: {}", e);
+                continue;
+            },
+        };
+        let store = Arc::clone(store);
+        thread::spawn(move || {
+            let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+            eprintln!("follower {} connected", peer);
+            if let Err(e) = lead(&store, stream) {
+                eprintln!("follower {} went away: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// applies whatever the leader sends, reconnecting whenever it goes away - for as long as we're running
+fn follow_leader(store: &ActionKV, leader: &str) {
+    // kept across reconnects, so the leader only has to send what we missed while it was gone -
+    // and follow() saves it next to the store, so the same goes for a restart
+    let mut position = None;
+    loop {
+        let result = TcpStream::connect(leader).and_then(|stream| store.follow(stream, &mut position));
+        match result {
+            Ok(()) => eprintln!("leader {} hung up", leader),
+            Err(e) => eprintln!("lost leader {}: {}", leader, e),
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// handles one connection until the client goes away (or says QUIT)
+/// a read-only server (a follower) turns down anything that would write to the store
+fn serve(store: &ActionKV, stream: TcpStream, read_only: bool) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            // the client hung up between commands - that's a perfectly normal way to finish
+            Ok(None) => return Ok(()),
+            // a malformed request leaves us with no idea where the next one starts, so tell the client and hang up
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                write_reply(&mut writer, &Reply::Error(format!("ERR Protocol error: {}", e)))?;
+                return writer.flush();
+            },
+            Err(e) => return Err(e),
+        };
+        if command.is_empty() {
+            continue;
+        }
+
+        let quit = command[0].eq_ignore_ascii_case(b"quit");
+        let reply = if quit { Reply::Simple("OK") } else { execute(store, &command, read_only) };
+        write_reply(&mut writer, &reply)?;
+        // pipelined commands get their replies in one go, but nobody should be left waiting for theirs
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+fn execute(store: &ActionKV, command: &[Vec<u8>], read_only: bool) -> Reply {
+    let name = String::from_utf8_lossy(&command[0]).to_ascii_uppercase();
+    let args = &command[1..];
+    // same reply redis gives on a replica
+    if read_only && matches!(name.as_str(), "SET" | "DEL") {
+        return Reply::Error("READONLY You can't write against a read only replica.".to_string());
+    }
+    let result = match (name.as_str(), args) {
+        ("PING", []) => Ok(Reply::Simple("PONG")),
+        ("PING", [message]) => Ok(Reply::Bulk(message.clone())),
+        // redis-cli asks for the command table when it connects - it copes fine with an empty one
+        ("COMMAND", _) => Ok(Reply::Array(vec![])),
+        ("GET", [key]) => store.get(key).map(|value| value.map_or(Reply::Null, Reply::Bulk)),
+        ("SET", [key, value, options @ ..]) => set(store, key, value, options),
+        ("DEL", keys) if !keys.is_empty() => delete(store, keys),
+        ("EXISTS", keys) if !keys.is_empty() => count_existing(store, keys),
+        ("KEYS", [pattern]) => sorted_keys(store)
+            .filter(|key| key.as_ref().map_or(true, |key| glob_match(pattern, key)))
+            .map(|key| key.map(Reply::Bulk))
+            .collect::<io::Result<_>>()
+            .map(Reply::Array),
+        ("SCAN", [cursor, options @ ..]) => scan(store, cursor, options),
+        ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) | ("KEYS", _) | ("SCAN", _) => {
+            Ok(Reply::Error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase())))
+        },
+        _ => Ok(Reply::Error(format!("ERR unknown command '{}'", name.to_lowercase()))),
+    };
+    result.unwrap_or_else(|e| Reply::Error(format!("ERR {}", e)))
+}
+
+/// SET key value [EX seconds | PX milliseconds]
+fn set(store: &ActionKV, key: &[u8], value: &[u8], options: &[Vec<u8>]) -> io::Result<Reply> {
+    let ttl = match options {
+        [] => None,
+        [unit, amount] => {
+            let amount = match parse_number(amount) {
+                Some(amount) if amount > 0 => amount as u64,
+                _ => return Ok(Reply::Error("ERR invalid expire time in 'set' command".to_string())),
+            };
+            if unit.eq_ignore_ascii_case(b"ex") {
+                Some(Duration::from_secs(amount))
+            } else if unit.eq_ignore_ascii_case(b"px") {
+                Some(Duration::from_millis(amount))
+            } else {
+                return Ok(Reply::Error("ERR syntax error".to_string()));
+            }
+        },
+        _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+    };
+    match ttl {
+        Some(ttl) => store.insert_with_ttl(key, value, ttl)?,
+        None => store.insert(key, value)?,
+    }
+    Ok(Reply::Simple("OK"))
+}
+
+/// DEL replies with how many of the keys were actually there
+fn delete(store: &ActionKV, keys: &[Vec<u8>]) -> io::Result<Reply> {
+    let mut deleted = 0;
+    for key in keys {
+        if store.contains_key(key)? {
+            store.delete(key)?;
+            deleted += 1;
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+/// EXISTS - redis counts a key once for every time it's named, so we do too
+fn count_existing(store: &ActionKV, keys: &[Vec<u8>]) -> io::Result<Reply> {
+    let mut count = 0;
+    for key in keys {
+        if store.contains_key(key)? {
+            count += 1;
+        }
+    }
+    Ok(Reply::Integer(count))
+}
+
+/// every key, in key order - from the index where it holds the keys, and a compact() part way through doesn't matter
+fn sorted_keys(store: &ActionKV) -> impl Iterator<Item = io::Result<Vec<u8>>> + '_ {
+    store.range(..).keys()
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count]
+/// the cursor is the last key looked at, hex encoded after a "k" (0 starts a scan, and ends one) - the next call
+/// carries on from the key after it, so a key that's there the whole time comes back exactly once
+/// however many get added or deleted in between
+fn scan(store: &ActionKV, cursor: &[u8], options: &[Vec<u8>]) -> io::Result<Reply> {
+    let after = match decode_cursor(cursor) {
+        Some(after) => after,
+        None => return Ok(Reply::Error("ERR invalid cursor".to_string())),
+    };
+    let mut pattern: &[u8] = b"*";
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"match") => pattern = value,
+            [name, value] if name.eq_ignore_ascii_case(b"count") => match parse_number(value) {
+                Some(n) if n > 0 => count = n as usize,
+                _ => return Ok(Reply::Error("ERR value is not an integer or out of range".to_string())),
+            },
+            _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+        }
+    }
+
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut keys = store.range((start, Bound::Unbounded)).keys();
+    // like redis, COUNT is how many keys get looked at rather than how many come back
+    let mut looked_at = Vec::with_capacity(count);
+    for key in keys.by_ref().take(count) {
+        looked_at.push(key?);
+    }
+    let next = match looked_at.last() {
+        Some(last) if keys.next().is_some() => encode_cursor(last),
+        _ => b"0".to_vec(),
+    };
+    let matching = looked_at
+        .into_iter()
+        .filter(|key| glob_match(pattern, key))
+        .map(Reply::Bulk)
+        .collect();
+    Ok(Reply::Array(vec![Reply::Bulk(next), Reply::Array(matching)]))
+}
+
+fn encode_cursor(last: &[u8]) -> Vec<u8> {
+    let mut cursor = b"k".to_vec();
+    for byte in last {
+        cursor.extend_from_slice(format!("{:02x}", byte).as_bytes());
+    }
+    cursor
+}
+
+/// Some(None) for a scan that's just starting
+fn decode_cursor(cursor: &[u8]) -> Option<Option<Vec<u8>>> {
+    if cursor == b"0" {
+        return Some(None);
+    }
+    let hex = std::str::from_utf8(cursor.strip_prefix(b"k")?).ok()?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .map(Some)
+}
+
+/// redis style glob: * matches anything, ? any single byte, \ makes the next byte literal
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'\\', [literal, rest @ ..])) => key.first() == Some(literal) && glob_match(rest, &key[1..]),
+        Some((literal, rest)) => key.first() == Some(literal) && glob_match(rest, &key[1..]),
+    }
+}
+
+fn parse_number(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+/// reads one command - an array of bulk strings, which is what clients send
+/// a plain line of space separated words (an "inline command") is accepted too, so telnet/nc work
+/// None means the connection was closed cleanly before the next command started
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(line.split(|byte| byte.is_ascii_whitespace()).filter(|word| !word.is_empty()).map(<[u8]>::to_vec).collect()));
+    }
+
+    let count = parse_length(&line[1..], MAX_ARGS)?;
+    let mut command = Vec::with_capacity(count.min(64) as usize);
+    for _ in 0..count {
+        let header = read_line(reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if header.first() != Some(&b'$') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected '$'"));
+        }
+        let len = parse_length(&header[1..], MAX_BULK_LEN)?;
+        // never trust a length enough to allocate it up front
+        let mut arg = Vec::with_capacity(len.min(64 * 1024) as usize);
+        reader.by_ref().take(len).read_to_end(&mut arg)?;
+        if arg.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected CRLF after bulk string"));
+        }
+        command.push(arg);
+    }
+    Ok(Some(command))
+}
+
+/// one CRLF (or just LF) terminated line, without the terminator
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    // a line is a header or an inline command, neither of which has any business being long
+    if reader.by_ref().take(64 * 1024).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long or cut short"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(bytes: &[u8], max: u64) -> io::Result<u64> {
+    match std::str::from_utf8(bytes).ok().and_then(|text| text.parse::<u64>().ok()) {
+        Some(len) if len <= max => Ok(len),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid length")),
+    }
+}
+
+fn write_reply<W: Write>(w: &mut W, reply: &Reply) -> io::Result<()> {
+    match reply {
+        Reply::Simple(text) => write!(w, "+{}\r\n", text),
+        // an error message can't contain a line break, or it would end the reply early
+        Reply::Error(text) => write!(w, "-{}\r\n", text.replace(['\r', '\n'], " ")),
+        Reply::Integer(n) => write!(w, ":{}\r\n", n),
+        Reply::Bulk(bytes) => {
+            write!(w, "${}\r\n", bytes.len())?;
+            w.write_all(bytes)?;
+            w.write_all(b"\r\n")
+        },
+        Reply::Null => w.write_all(b"$-1\r\n"),
+        Reply::Array(items) => {
+            write!(w, "*{}\r\n", items.len())?;
+            for item in items {
+                write_reply(w, item)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_follows_redis() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"user:*", b"user:1"));
+        assert!(!glob_match(b"user:*", b"users"));
+        assert!(glob_match(b"a*b*c", b"axxbyyc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyy"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"\\*", b"*"));
+        assert!(!glob_match(b"\\*", b"x"));
+        assert!(!glob_match(b"", b"x"));
+    }
+
+    #[test]
+    fn read_command_takes_resp_and_inline_commands() {
+        let mut input = &b"*2\r\n$3\r\nGET\r\n$5\r\na\r\nb\xff\r\nSET  k v\n"[..];
+        assert_eq!(read_command(&mut input).unwrap(), Some(vec![b"GET".to_vec(), b"a\r\nb\xff".to_vec()]));
+        assert_eq!(read_command(&mut input).unwrap(), Some(vec![b"SET".to_vec(), b"k".to_vec(), b"v".to_vec()]));
+        assert_eq!(read_command(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn read_command_rejects_broken_input() {
+        let broken: [&[u8]; 5] = [
+            b"*1\r\n$3\r\nGE",              // cut short
+            b"*1\r\n+GET\r\n",              // not a bulk string
+            b"*1\r\n$3\r\nGETxx",           // no CRLF after the bulk string
+            b"*1\r\n$999999999999\r\n",     // longer than we'll take
+            b"*x\r\n",                      // not a number
+        ];
+        for input in broken.iter() {
+            assert!(read_command(&mut &input[..]).is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[test]
+    fn scan_cursors_round_trip() {
+        for key in [&b""[..], b"0", b"key\x00\xff"].iter() {
+            assert_eq!(decode_cursor(&encode_cursor(key)), Some(Some(key.to_vec())));
+        }
+        assert_eq!(decode_cursor(b"0"), Some(None));
+        assert_eq!(decode_cursor(b"k0"), None);
+        assert_eq!(decode_cursor(b"12"), None);
+    }
+}
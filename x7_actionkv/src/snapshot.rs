@@ -0,0 +1,122 @@
+//! backups: a snapshot is a copy of every live key as of one point in the log, written out as a compacted single data file
+//! it's a store in its own right - open() it to look inside, or restore() it to put it back in place of a store
+//!
+//! only the cut itself happens under the write lock, the copy runs alongside writers and readers
+//! it reads the records through the segments as they were at the cut, which stay readable even if compact() swaps them out
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{format, generation, lock};
+use crate::segment::{self, Segment};
+use crate::{now_millis, ActionKV, Corruption, LogOffset, Position};
+
+impl ActionKV {
+    /// writes every key that's live right now into a new data file at dest, returning how far into the log that is
+    /// anything written after the returned offset isn't in the snapshot - subscribe() from it to pick up the rest,
+    /// eg to bring a standby made from the snapshot up to date
+    /// that only works until this store's next compact(), after that subscribe() fails with Compacted -
+    /// there's no telling any more which writes came after the cut, so take a new snapshot
+    ///
+    /// a crash part way through leaves dest alone, the snapshot is written next to it and renamed into place once it's complete
+    /// the positions in the index are copied at the cut, so this needs memory for every live record (but not its key or value)
+    pub fn snapshot(&self, dest: &Path) -> io::Result<LogOffset> {
+        // with the write lock held nothing is half written, so the index describes the log up to exactly its end
+        let (mut live, segments, end) = {
+            let _writer = self.writer.lock().unwrap();
+            let state = self.state();
+            let live: Vec<Position> = state.index.positions().copied().collect();
+            let segments: BTreeMap<u32, Arc<Segment>> = state.segments.clone();
+            let end = LogOffset { segment: state.active_id(), offset: state.active().len()?, generation: state.generation };
+            (live, segments, end)
+        };
+        // front to back through the files, same as compact()
+        live.sort_by_key(|position| (position.segment, position.offset));
+
+        let tmp_path = ActionKV::sibling_path(dest, "snapshot");
+        let tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut w = BufWriter::new(tmp);
+        w.write_all(&format::header())?;
+
+        let now = now_millis();
+        for position in live {
+            let segment = segments
+                .get(&position.segment)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no segment with id {}", position.segment)))?;
+            let record = ActionKV::read_record(segment, position)?;
+            if record.is_expired(now) {
+                continue;
+            }
+            self.write_value(&mut w, &record.kv.key, &record.kv.value, 0, record.expires_at)?;
+        }
+
+        let tmp = w.into_inner().map_err(|e| e.into_error())?;
+        tmp.sync_all()?;
+        drop(tmp);
+        fs::rename(&tmp_path, dest)?;
+        ActionKV::sync_parent_dir(dest)?;
+        Ok(end)
+    }
+
+    /// replaces the store at path with a snapshot - whatever the store held before is gone
+    /// path is whatever you'd pass to open(), a data file or a directory of segments; neither has to exist yet
+    /// (but a segmented store's directory does, otherwise there's no telling it apart from a single file)
+    /// the store can't be open while this runs (that's what its lock is for) - and the snapshot is checked over before anything gets touched
+    /// it counts as a compaction, so offsets the store handed out before are stale afterwards
+    pub fn restore(snapshot: &Path, path: &Path) -> io::Result<()> {
+        ActionKV::check_snapshot(snapshot)?;
+        let segmented = path.is_dir();
+        let _lock = lock::acquire(&ActionKV::aux_path_of(path, segmented, "lock"), path, true)?;
+
+        // the restored file is named so that it covers every segment there is,
+        // which makes segment::list() get rid of them - now, or on the next open if we crash before then
+        let target = if segmented {
+            let last = segment::list(path, true)?.keys().next_back().copied().unwrap_or(1);
+            path.join(segment::file_name(1, last))
+        } else {
+            path.to_path_buf()
+        };
+        let hint_path = ActionKV::aux_path_of(path, segmented, "hint");
+
+        let tmp_path = ActionKV::sibling_path(&target, "restore");
+        fs::copy(snapshot, &tmp_path)?;
+        OpenOptions::new().write(true).open(&tmp_path)?.sync_all()?;
+
+        // the hint describes the old data, it mustn't outlive it
+        ActionKV::remove_if_exists(&hint_path)?;
+        // and the generation has to move on before the data does, same as in compact()
+        let generation_path = ActionKV::aux_path_of(path, segmented, "generation");
+        let generation = generation::read(&generation_path)?.unwrap_or(0);
+        generation::write(&generation_path, generation::next(generation))?;
+        fs::rename(&tmp_path, &target)?;
+        ActionKV::sync_parent_dir(&target)?;
+        if segmented {
+            segment::list(path, true)?;
+        }
+        Ok(())
+    }
+
+    /// same checks as verify(), but without opening the snapshot as a store - that would want to lock it
+    fn check_snapshot(snapshot: &Path) -> io::Result<()> {
+        let f = File::open(snapshot)?;
+        format::check(&f, snapshot)?;
+        let segment = Segment::new(0, Some(snapshot.to_path_buf()), Arc::new(f));
+        let mut damaged = None;
+        ActionKV::for_each_record(&segment, |offset, _, record| {
+            if let (Err(e), None) = (record, &damaged) {
+                damaged = Some(Corruption::locate(e, 0, offset));
+            }
+        })?;
+        match damaged {
+            Some(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("snapshot {} is damaged: {}", snapshot.display(), e))),
+            None => Ok(()),
+        }
+    }
+}
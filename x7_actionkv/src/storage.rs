@@ -0,0 +1,181 @@
+//! what a segment keeps its bytes in - a file on disk normally, but anything that can be appended to and read back at an offset will do
+//! ActionKV::with_storage() builds a store on top of any Storage, ActionKV::in_memory() on a MemoryStorage,
+//! for tests and caches that have no business touching the disk
+//!
+//! a store that isn't kept in files has no path, so it goes without the things that live next to the data:
+//! no hint (load() reads every record), no lock, and recover() doesn't keep the damaged records it skips
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::{Arc, RwLock};
+
+/// an append-only run of bytes
+/// every method takes &self - reads happen from many threads at once, while appends only ever come from one at a time
+pub trait Storage: Send + Sync + fmt::Debug {
+    fn len(&self) -> io::Result<u64>;
+
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// reads into buf from offset, same contract as Read::read - fewer bytes than asked for (0 at the end) is fine
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// adds buf to the end, all of it or (on error) as little as possible
+    fn append(&self, buf: &[u8]) -> io::Result<()>;
+
+    /// makes everything appended so far survive a crash, if that means anything for this kind of storage
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// cuts off everything from len onwards - only recover() does this, to get rid of a torn record
+    fn truncate(&self, len: u64) -> io::Result<()>;
+
+    /// a new, empty storage of the same kind - compact() writes the live records into it and then swaps it in
+    fn empty(&self) -> io::Result<Arc<dyn Storage>>;
+
+    /// the file underneath, if there is one - only files can be read through a memory map (see Options::mmap)
+    fn file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl Storage for File {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    /// pread, so the file's cursor is left alone and any number of threads can read at once
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        #[cfg(not(target_os = "windows"))]
+        {
+            use std::os::unix::fs::FileExt;
+            FileExt::read_at(self, buf, offset)
+        }
+        #[cfg(target_os = "windows")]
+        {
+            use std::os::windows::fs::FileExt;
+            self.seek_read(buf, offset)
+        }
+    }
+
+    /// data files are opened in append mode, so this always lands at the end
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        let mut f = self;
+        f.write_all(buf)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.set_len(len)?;
+        self.sync_all()
+    }
+
+    /// file backed stores are compacted into a new file next to the old one, which needs the path - ActionKV takes care of that itself
+    fn empty(&self) -> io::Result<Arc<dyn Storage>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "a file doesn't know where a new one should go"))
+    }
+
+    fn file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+/// keeps everything in a Vec<u8> - gone as soon as the store is dropped
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// a copy of everything in it - the same bytes a file backed store would have on disk
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.data.read().unwrap().clone()
+    }
+}
+
+impl From<Vec<u8>> for MemoryStorage {
+    /// eg the contents of a data file, to load it without touching the file itself
+    fn from(data: Vec<u8>) -> Self {
+        MemoryStorage { data: RwLock::new(data) }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = offset.min(data.len() as u64) as usize;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn append(&self, buf: &[u8]) -> io::Result<()> {
+        self.data.write().unwrap().extend_from_slice(buf);
+        Ok(())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.data.write().unwrap().truncate(len as usize);
+        Ok(())
+    }
+
+    fn empty(&self) -> io::Result<Arc<dyn Storage>> {
+        Ok(Arc::new(MemoryStorage::new()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::data_file;
+    use crate::{ActionKV, MemoryStorage, Options, FLAG_TOMBSTONE};
+
+    /// everything in the store's only segment
+    fn contents(store: &ActionKV) -> Vec<u8> {
+        let state = store.state();
+        let segment = state.active();
+        let mut data = vec![0; segment.len().unwrap() as usize];
+        segment.read_exact_at(&mut data, 0).unwrap();
+        data
+    }
+
+    #[test]
+    fn compact_keeps_live_keys_and_drops_the_rest() {
+        let data = data_file(&[
+            (b"a", b"1", 0),
+            (b"b", b"2", 0),
+            (b"a", b"3", 0),
+            (b"c", b"4", 0),
+            (b"b", b"", FLAG_TOMBSTONE),
+        ]);
+        let mut store = ActionKV::with_storage(MemoryStorage::from(data), Options::default()).unwrap();
+        store.load().unwrap();
+        store.compact().unwrap();
+
+        // the old version of a, b and b's tombstone are gone - the live records are left, in the order they were written
+        assert_eq!(contents(&store), data_file(&[(b"a", b"3", 0), (b"c", b"4", 0)]));
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+
+        // and writes carry on in the compacted storage
+        store.insert(b"d", b"5").unwrap();
+        let mut reloaded = ActionKV::with_storage(MemoryStorage::from(contents(&store)), Options::default()).unwrap();
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.len(), 3);
+        assert_eq!(reloaded.get(b"d").unwrap(), Some(b"5".to_vec()));
+    }
+}
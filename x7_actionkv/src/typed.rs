@@ -0,0 +1,106 @@
+//! a typed layer on top of ActionKV, so application code can store its own types instead of hand-serialising everything
+//! keys and values go through a Codec on their way in and out - bincode unless you plug in something else
+
+use std::io;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{ActionKV, ByteStr, ByteString};
+
+/// turns values into bytes and back again
+/// decoding errors come back as io::Errors of kind InvalidData, same as the rest of the store
+pub trait Codec {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> io::Result<ByteString>;
+    fn decode<T: DeserializeOwned>(bytes: &ByteStr) -> io::Result<T>;
+}
+
+/// the default codec - compact, fast, and not meant to be read by humans
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized>(value: &T) -> io::Result<ByteString> {
+        bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &ByteStr) -> io::Result<T> {
+        bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// an ActionKV where every key is a K and every value a V
+/// the underlying store is still there for load(), compact() and friends - see store() and store_mut()
+///
+/// note keys are compared as encoded bytes, which for bincode doesn't match the order of the keys themselves
+/// (integers are little endian) - that's why there's no typed range()
+#[derive(Debug)]
+pub struct TypedKV<K, V, C = Bincode> {
+    store: ActionKV,
+    types: Types<K, V, C>,
+}
+
+/// fn() -> .. keeps TypedKV Send + Sync whatever K and V are - we never actually hold on to one
+type Types<K, V, C> = PhantomData<fn() -> (K, V, C)>;
+
+impl<K, V, C> TypedKV<K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// wraps an already opened store - remember to load() it first
+    pub fn new(store: ActionKV) -> Self {
+        TypedKV { store, types: PhantomData }
+    }
+
+    pub fn store(&self) -> &ActionKV {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut ActionKV {
+        &mut self.store
+    }
+
+    pub fn into_inner(self) -> ActionKV {
+        self.store
+    }
+
+    pub fn get(&self, key: &K) -> io::Result<Option<V>> {
+        match self.store.get_value(&C::encode(key)?)? {
+            Some(value) => C::decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &K) -> io::Result<bool> {
+        self.store.contains_key(&C::encode(key)?)
+    }
+
+    pub fn insert(&self, key: &K, value: &V) -> io::Result<()> {
+        self.store.insert(&C::encode(key)?, &C::encode(value)?)
+    }
+
+    pub fn insert_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> io::Result<()> {
+        self.store.insert_with_ttl(&C::encode(key)?, &C::encode(value)?, ttl)
+    }
+
+    pub fn delete(&self, key: &K) -> io::Result<()> {
+        self.store.delete(&C::encode(key)?)
+    }
+
+    /// every key, decoded - a key that doesn't decode (say, one written through the raw store) comes back as an error
+    pub fn keys(&self) -> impl Iterator<Item = io::Result<K>> + '_ {
+        self.store.keys().map(|key| C::decode(&key?))
+    }
+
+    /// every key/value pair, decoded - same order as ActionKV::iter()
+    pub fn iter(&self) -> impl Iterator<Item = io::Result<(K, V)>> + '_ {
+        self.store.iter().map(|kv| {
+            let kv = kv?;
+            Ok((C::decode(&kv.key)?, C::decode(&kv.value)?))
+        })
+    }
+}
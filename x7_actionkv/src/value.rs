@@ -0,0 +1,78 @@
+//! what get_value() hands back
+//! with Options::mmap it's a window straight into the mapped segment file, so reading it doesn't copy anything -
+//! otherwise it just owns the bytes that were read
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+
+use crate::{ByteStr, ByteString};
+
+/// derefs to the value's bytes, so it can be used pretty much anywhere a &[u8] can
+/// a mapped value keeps its segment's map alive, even if compact() has replaced the file since - drop it once you're done
+pub struct Value {
+    inner: Inner,
+}
+
+enum Inner {
+    Mapped { map: Arc<Mmap>, start: usize, end: usize },
+    Owned(ByteString),
+}
+
+impl Value {
+    pub(crate) fn mapped(map: Arc<Mmap>, start: usize, end: usize) -> Self {
+        Value { inner: Inner::Mapped { map, start, end } }
+    }
+
+    /// true if the bytes live in the segment's map rather than a buffer of their own
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.inner, Inner::Mapped { .. })
+    }
+
+    /// copies the value out, unless it was already an owned one
+    pub fn into_vec(self) -> ByteString {
+        match self.inner {
+            Inner::Mapped { .. } => self.to_vec(),
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl From<ByteString> for Value {
+    fn from(value: ByteString) -> Self {
+        Value { inner: Inner::Owned(value) }
+    }
+}
+
+impl Deref for Value {
+    type Target = ByteStr;
+
+    fn deref(&self) -> &ByteStr {
+        match &self.inner {
+            Inner::Mapped { map, start, end } => &map[*start..*end],
+            Inner::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<ByteStr> for Value {
+    fn as_ref(&self) -> &ByteStr {
+        self
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Value {}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Value").field(&&**self).finish()
+    }
+}